@@ -0,0 +1,69 @@
+//! Iterable index of the accounts holding balances in the AMM.
+//!
+//! `FungibleToken.accounts` is a `LookupMap`, so the accounts are also kept
+//! in an `UnorderedSet` when they register with `storage_deposit` or deposit
+//! tokens, and leave it once unregistered from all three tokens. The internal
+//! accounts of the contract (`<name>.<amm>`) are not listed. Accounts of a
+//! migrated state are listed once they deposit, or once the owner backfills
+//! them with `index_accounts`.
+
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+use near_sdk::{near_bindgen, AccountId};
+
+use crate::*;
+
+#[near_bindgen]
+impl AMM {
+    pub fn get_number_of_accounts(&self) -> u64 {
+        self.accounts.len()
+    }
+
+    /// Accounts with their internal token_a, token_b and LP share balances.
+    pub fn get_accounts(&self, from_index: u64, limit: u64) -> String {
+        let accounts = self.accounts.as_vector();
+        let balances: Vec<_> = (from_index..accounts.len().min(from_index.saturating_add(limit)))
+            .map(|index| {
+                let account_id = accounts.get(index).unwrap();
+                json!({
+                    "account_id": account_id,
+                    "token_a": U128(self.token_a.0.accounts.get(&account_id).unwrap_or(0)),
+                    "token_b": U128(self.token_b.0.accounts.get(&account_id).unwrap_or(0)),
+                    "shares": U128(self.token_amm.accounts.get(&account_id).unwrap_or(0)),
+                })
+            })
+            .collect();
+        json!(balances).to_string()
+    }
+
+    /// Adds the accounts registered on any of the tokens to the index,
+    /// returns how many were missing. Pass them in pages small enough for
+    /// the gas of one call.
+    pub fn index_accounts(&mut self, account_ids: Vec<AccountId>) -> u64 {
+        self.assert_owner();
+        let mut indexed = 0;
+        for account_id in account_ids {
+            if self.is_registered(&account_id) && self.accounts.insert(&account_id) {
+                indexed += 1;
+            }
+        }
+        indexed
+    }
+
+    pub(crate) fn index_account(&mut self, account_id: &AccountId) {
+        self.accounts.insert(account_id);
+    }
+
+    /// Drops the account from the index once it left all three tokens.
+    pub(crate) fn unindex_account(&mut self, account_id: &AccountId) {
+        if !self.is_registered(account_id) {
+            self.accounts.remove(account_id);
+        }
+    }
+
+    fn is_registered(&self, account_id: &AccountId) -> bool {
+        self.token_amm.accounts.contains_key(account_id)
+            || self.token_a.0.accounts.contains_key(account_id)
+            || self.token_b.0.accounts.contains_key(account_id)
+    }
+}
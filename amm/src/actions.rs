@@ -0,0 +1,89 @@
+//! Batches of actions.
+//!
+//! `execute_actions` runs several operations on the internal balances of the
+//! caller in one call. The state only changes if every action succeeds: any
+//! panic reverts the whole batch. Withdrawals are the exception once the
+//! batch went through, their transfers run in later receipts and a failed
+//! transfer refunds the internal balance as `withdraw_tokens` does.
+
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId};
+
+use crate::*;
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum Action {
+    /// Same as `swap`, results in the bought amount.
+    Swap {
+        buy_token_name: AccountId,
+        sell_token_name: AccountId,
+        sell_amount: U128,
+        referral_id: Option<AccountId>,
+    },
+    /// Same as `add_token_to_pool`, results in the minted shares.
+    AddTokenToPool {
+        token_name: AccountId,
+        token_amount: U128,
+    },
+    /// Same as `exclude_token_from_pool`, results in the burned shares.
+    ExcludeTokenFromPool {
+        token_name: AccountId,
+        token_amount: U128,
+    },
+    /// Same as `withdraw_tokens`, results in the withdrawn amount.
+    WithdrawTokens { token_name: AccountId, amount: U128 },
+}
+
+#[near_bindgen]
+impl AMM {
+    /// Runs the actions in order for the caller, returns the result of each.
+    #[payable]
+    pub fn execute_actions(&mut self, actions: Vec<Action>) -> Vec<U128> {
+        self.internal_execute_actions(&env::predecessor_account_id(), actions)
+    }
+
+    pub(crate) fn internal_execute_actions(
+        &mut self,
+        account_id: &AccountId,
+        actions: Vec<Action>,
+    ) -> Vec<U128> {
+        if actions.is_empty() {
+            panic!("Nothing to execute");
+        }
+        actions
+            .into_iter()
+            .map(|action| U128(self.internal_execute_action(account_id, action)))
+            .collect()
+    }
+
+    fn internal_execute_action(&mut self, account_id: &AccountId, action: Action) -> Balance {
+        match action {
+            Action::Swap {
+                buy_token_name,
+                sell_token_name,
+                sell_amount,
+                referral_id,
+            } => self.internal_swap(
+                account_id,
+                &buy_token_name,
+                &sell_token_name,
+                sell_amount.0,
+                referral_id.as_ref(),
+            ),
+            Action::AddTokenToPool {
+                token_name,
+                token_amount,
+            } => self.internal_add_to_pool(account_id, &token_name, token_amount.0, None),
+            Action::ExcludeTokenFromPool {
+                token_name,
+                token_amount,
+            } => self.internal_exclude_from_pool(account_id, &token_name, token_amount.0, None),
+            Action::WithdrawTokens { token_name, amount } => {
+                self.internal_withdraw_tokens(account_id.clone(), token_name, amount);
+                amount.0
+            }
+        }
+    }
+}
@@ -0,0 +1,235 @@
+//! Dollar-cost-averaging schedules.
+//!
+//! Creating a schedule moves `amount_per_swap * total_swaps` from the internal
+//! balance of the owner to an escrow account. Anyone can call
+//! `execute_due_schedules`; every schedule whose time has come sells one
+//! `amount_per_swap` through the pool, unless that would return less than its
+//! `min_buy_amount`, in which case it waits for the next call. The next swap
+//! is due `interval` seconds after the later of its due time and the last
+//! swap, so late swaps never run in a burst.
+//!
+//! As for limit orders, the owner pays the storage of its schedule, which is
+//! refunded once the schedule is done or canceled, and schedules are indexed
+//! per owner.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::{json, Value};
+use near_sdk::{env, log, near_bindgen, AccountId, Balance};
+
+use crate::*;
+
+const NANOSECONDS_IN_SECOND: u64 = 1_000_000_000;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Schedule {
+    pub owner_id: AccountId,
+    pub sell_token_name: AccountId,
+    pub buy_token_name: AccountId,
+    pub amount_per_swap: Balance,
+    /// Least amount bought by a single swap.
+    pub min_buy_amount: Balance,
+    /// Seconds between two swaps.
+    pub interval: u64,
+    pub total_swaps: u32,
+    pub executed_swaps: u32,
+    /// Block timestamp, in nanoseconds, from which the next swap is due.
+    pub next_swap_at: u64,
+    pub bought: Balance,
+}
+
+impl Schedule {
+    fn remaining_escrow(&self) -> Balance {
+        self.amount_per_swap * (self.total_swaps - self.executed_swaps) as Balance
+    }
+}
+
+fn dca_account() -> AccountId {
+    internal_account("dca")
+}
+
+#[near_bindgen]
+impl AMM {
+    /// Escrows `amount_per_swap * total_swaps` and sells `amount_per_swap`
+    /// every `interval` seconds, starting now. Returns the schedule id.
+    /// Attach the storage cost of the schedule, the rest is refunded.
+    #[payable]
+    pub fn create_schedule(
+        &mut self,
+        sell_token_name: AccountId,
+        buy_token_name: AccountId,
+        amount_per_swap: U128,
+        min_buy_amount: U128,
+        interval: u64,
+        total_swaps: u32,
+    ) -> u64 {
+        if buy_token_name.eq(&sell_token_name) {
+            panic!("Tokens can't be equals")
+        }
+        if amount_per_swap.0 == 0 || total_swaps == 0 {
+            panic!("The schedule has nothing to swap");
+        }
+        if interval == 0 || interval.checked_mul(NANOSECONDS_IN_SECOND).is_none() {
+            panic!(
+                "The interval must be between 1 and {} seconds",
+                u64::MAX / NANOSECONDS_IN_SECOND
+            );
+        }
+        let owner_id = env::predecessor_account_id();
+        if !self.get_token_by_name(&buy_token_name).0.accounts.contains_key(&owner_id) {
+            panic!("Please register for the bought token first");
+        }
+        let escrow = amount_per_swap
+            .0
+            .checked_mul(total_swaps as Balance)
+            .expect("The schedule is too large");
+
+        let initial_storage = env::storage_usage();
+        let escrow_id = dca_account();
+        register_account(&mut self.token_a.0, &escrow_id);
+        register_account(&mut self.token_b.0, &escrow_id);
+        let token = self.get_token_by_name_as_ref(&sell_token_name);
+        token.0.internal_transfer(&owner_id, &escrow_id, escrow, None);
+
+        let schedule_id = self.next_schedule_id;
+        self.next_schedule_id += 1;
+        self.schedules.insert(
+            &schedule_id,
+            &Schedule {
+                owner_id: owner_id.clone(),
+                sell_token_name,
+                buy_token_name,
+                amount_per_swap: amount_per_swap.0,
+                min_buy_amount: min_buy_amount.0,
+                interval,
+                total_swaps,
+                executed_swaps: 0,
+                next_swap_at: env::block_timestamp(),
+                bought: 0,
+            },
+        );
+        index_insert(&mut self.account_schedules, b"c", &owner_id, schedule_id);
+        settle_storage(&owner_id, initial_storage, env::attached_deposit());
+        schedule_id
+    }
+
+    /// Stops the schedule and gives the remaining escrow back to the owner.
+    pub fn cancel_schedule(&mut self, schedule_id: u64) {
+        let schedule = self.schedules.get(&schedule_id).expect("Schedule not found");
+        if schedule.owner_id != env::predecessor_account_id() {
+            panic!("Only the owner of the schedule can cancel it");
+        }
+        self.remove_schedule(schedule_id, &schedule.owner_id);
+        let token = self.get_token_by_name_as_ref(&schedule.sell_token_name);
+        token.0.internal_transfer(
+            &dca_account(),
+            &schedule.owner_id,
+            schedule.remaining_escrow(),
+            None,
+        );
+    }
+
+    /// Runs one swap of every due schedule, looking at `limit` schedules at
+    /// most. Returns the ids of the schedules that swapped.
+    pub fn execute_due_schedules(&mut self, from_index: u64, limit: u64) -> Vec<u64> {
+        let now = env::block_timestamp();
+        let due: Vec<_> = self
+            .schedules
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .filter(|(_, schedule)| schedule.next_swap_at <= now)
+            .collect();
+
+        let escrow_id = dca_account();
+        let mut executed = vec![];
+        for (schedule_id, mut schedule) in due {
+            let quote = self.quote_swap(
+                &schedule.buy_token_name,
+                &schedule.sell_token_name,
+                schedule.amount_per_swap,
+            );
+            if quote == 0 || quote < schedule.min_buy_amount {
+                log!("Schedule {} skipped, the pool returns {}", schedule_id, quote);
+                continue;
+            }
+
+            let bought = self.internal_swap(
+                &escrow_id,
+                &schedule.buy_token_name,
+                &schedule.sell_token_name,
+                schedule.amount_per_swap,
+                None,
+            );
+            let token = self.get_token_by_name_as_ref(&schedule.buy_token_name);
+            token
+                .0
+                .internal_transfer(&escrow_id, &schedule.owner_id, bought, None);
+
+            schedule.executed_swaps += 1;
+            schedule.bought += bought;
+            // A late swap doesn't let the next ones catch up
+            schedule.next_swap_at = max(now, schedule.next_swap_at)
+                .saturating_add(schedule.interval * NANOSECONDS_IN_SECOND);
+            if schedule.executed_swaps == schedule.total_swaps {
+                self.remove_schedule(schedule_id, &schedule.owner_id);
+            } else {
+                self.schedules.insert(&schedule_id, &schedule);
+            }
+            executed.push(schedule_id);
+        }
+        executed
+    }
+
+    pub fn get_schedule(&self, schedule_id: u64) -> Option<String> {
+        self.schedules
+            .get(&schedule_id)
+            .map(|schedule| schedule_json(schedule_id, &schedule).to_string())
+    }
+
+    pub fn get_schedules(&self, from_index: u64, limit: u64) -> String {
+        let schedules: Vec<_> = self
+            .schedules
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .map(|(schedule_id, schedule)| schedule_json(schedule_id, &schedule))
+            .collect();
+        json!(schedules).to_string()
+    }
+
+    pub fn get_account_schedules(&self, account_id: AccountId) -> String {
+        let schedules: Vec<_> = index_get(&self.account_schedules, &account_id)
+            .into_iter()
+            .map(|schedule_id| {
+                schedule_json(schedule_id, &self.schedules.get(&schedule_id).unwrap())
+            })
+            .collect();
+        json!(schedules).to_string()
+    }
+
+    /// Removes the schedule and refunds the storage it used to its owner.
+    fn remove_schedule(&mut self, schedule_id: u64, owner_id: &AccountId) {
+        let initial_storage = env::storage_usage();
+        self.schedules.remove(&schedule_id);
+        index_remove(&mut self.account_schedules, owner_id, schedule_id);
+        settle_storage(owner_id, initial_storage, 0);
+    }
+}
+
+fn schedule_json(schedule_id: u64, schedule: &Schedule) -> Value {
+    json!({
+        "schedule_id": schedule_id,
+        "owner_id": schedule.owner_id,
+        "sell_token_name": schedule.sell_token_name,
+        "buy_token_name": schedule.buy_token_name,
+        "amount_per_swap": U128(schedule.amount_per_swap),
+        "min_buy_amount": U128(schedule.min_buy_amount),
+        "interval": schedule.interval,
+        "total_swaps": schedule.total_swaps,
+        "executed_swaps": schedule.executed_swaps,
+        "next_swap_at": schedule.next_swap_at,
+        "bought": U128(schedule.bought),
+        "remaining_escrow": U128(schedule.remaining_escrow()),
+    })
+}
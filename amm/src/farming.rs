@@ -0,0 +1,384 @@
+//! Liquidity mining.
+//!
+//! LPs stake their `token_amm` shares into an escrow account and earn the
+//! reward tokens of every farm. A farm pays out the reward it holds linearly
+//! over the time left until its end, split between the stakers pro rata with
+//! the usual reward-per-share accounting. Anybody can fund a farm with
+//! `ft_transfer_call` and the `{"fund_farm": {"farm_id": <ID>}}` msg.
+//!
+//! Rewards in token A or B are kept on the internal balances, so they are
+//! claimed to the internal balance of the staker. Other reward tokens are
+//! sent with `ft_transfer`.
+//!
+//! As for limit orders, the stakers pay the storage of their entry, which
+//! grows with every farm; the calls that change it take the cost out of the
+//! attached deposit and refund the rest. The entry is dropped, and its
+//! storage refunded, once nothing is staked or left to claim. The reward a
+//! farm holds when it ends without stakers goes back to the owner with
+//! `withdraw_farm_leftover`.
+
+use std::collections::HashMap;
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::{json, Value};
+use near_sdk::{env, log, near_bindgen, AccountId, Balance, PromiseOrValue, PromiseResult};
+
+use crate::utils::U256;
+use crate::*;
+
+/// Scale of the reward per share.
+const REWARD_PRECISION: u128 = 1_000_000_000_000_000_000_000_000;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Farm {
+    pub reward_token_id: AccountId,
+    /// Block timestamps, in nanoseconds.
+    pub start_at: u64,
+    pub end_at: u64,
+    /// Reward not paid out yet.
+    pub undistributed: Balance,
+    pub last_update_at: u64,
+    /// Words of the U256 reward per staked share, scaled by `REWARD_PRECISION`.
+    pub reward_per_share: [u64; 4],
+}
+
+impl Farm {
+    fn reward_per_share(&self) -> U256 {
+        U256(self.reward_per_share)
+    }
+
+    /// Pays out the reward of the time elapsed since the last update.
+    fn update(&mut self, total_staked: Balance, now: u64) {
+        let now = now.min(self.end_at);
+        if now <= self.last_update_at {
+            return;
+        }
+        // Without stakers the reward waits for the remaining time
+        if total_staked > 0 && self.undistributed > 0 {
+            let released = (U256::from(self.undistributed) * U256::from(now - self.last_update_at)
+                / U256::from(self.end_at - self.last_update_at))
+            .as_u128();
+            self.undistributed -= released;
+            self.reward_per_share = (self.reward_per_share()
+                + U256::from(released) * U256::from(REWARD_PRECISION) / U256::from(total_staked))
+            .0;
+        }
+        self.last_update_at = now;
+    }
+
+    fn accrued(&self, staked: Balance) -> Balance {
+        (U256::from(staked) * self.reward_per_share() / U256::from(REWARD_PRECISION)).as_u128()
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Default, Clone)]
+pub struct FarmReward {
+    /// Part of `accrued` already accounted for.
+    pub debt: Balance,
+    pub unclaimed: Balance,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct Farmer {
+    pub staked: Balance,
+    pub rewards: HashMap<u64, FarmReward>,
+}
+
+pub(crate) fn farming_account() -> AccountId {
+    internal_account("farming")
+}
+
+#[near_bindgen]
+impl AMM {
+    /// Adds a farm paying `reward_token_id` between the two timestamps, in
+    /// nanoseconds. Returns the farm id.
+    pub fn add_farm(&mut self, reward_token_id: AccountId, start_at: u64, end_at: u64) -> u64 {
+        self.assert_owner();
+        if start_at >= end_at || end_at <= env::block_timestamp() {
+            panic!("Wrong farm period");
+        }
+        if self.is_pool_token(&reward_token_id) {
+            let token = self.get_token_by_name_as_ref(&reward_token_id);
+            register_account(&mut token.0, &farming_account());
+        }
+        self.farms.push(&Farm {
+            reward_token_id,
+            start_at,
+            end_at,
+            undistributed: 0,
+            last_update_at: start_at.max(env::block_timestamp()),
+            reward_per_share: U256::zero().0,
+        });
+        self.farms.len() - 1
+    }
+
+    /// Attach the storage cost of the stake, the rest is refunded.
+    #[payable]
+    pub fn stake_shares(&mut self, amount: U128) {
+        let account_id = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        let farming_id = farming_account();
+        register_account(&mut self.token_amm, &farming_id);
+        let staked = self.get_staked_shares(account_id.clone()).0;
+        self.update_farmer(&account_id, staked + amount.0);
+        self.token_amm
+            .internal_transfer(&account_id, &farming_id, amount.0, None);
+        settle_storage(&account_id, initial_storage, env::attached_deposit());
+    }
+
+    /// Gives the shares back, the rewards earned so far stay claimable.
+    #[payable]
+    pub fn unstake_shares(&mut self, amount: U128) {
+        let account_id = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        let staked = self.get_staked_shares(account_id.clone()).0;
+        if amount.0 > staked {
+            panic!("Not enough staked shares");
+        }
+        self.update_farmer(&account_id, staked - amount.0);
+        self.token_amm
+            .internal_transfer(&farming_account(), &account_id, amount.0, None);
+        settle_storage(&account_id, initial_storage, env::attached_deposit());
+    }
+
+    /// Pays out the rewards of the farm earned by the caller.
+    #[payable]
+    pub fn claim_rewards(&mut self, farm_id: u64) -> PromiseOrValue<U128> {
+        let reward_token_id = self.farms.get(farm_id).expect("Farm not found").reward_token_id;
+        let account_id = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        let staked = self.get_staked_shares(account_id.clone()).0;
+        self.update_farmer(&account_id, staked);
+        let amount = match self.farmers.get(&account_id) {
+            Some(mut farmer) => {
+                let amount = farmer
+                    .rewards
+                    .get_mut(&farm_id)
+                    .map_or(0, |reward| std::mem::take(&mut reward.unclaimed));
+                self.save_farmer(&account_id, &farmer);
+                amount
+            }
+            None => 0,
+        };
+        if amount == 0 {
+            settle_storage(&account_id, initial_storage, env::attached_deposit());
+            return PromiseOrValue::Value(U128(0));
+        }
+
+        if self.is_pool_token(&reward_token_id) {
+            let token = self.get_token_by_name_as_ref(&reward_token_id);
+            register_account(&mut token.0, &account_id);
+            token
+                .0
+                .internal_transfer(&farming_account(), &account_id, amount, None);
+            settle_storage(&account_id, initial_storage, env::attached_deposit());
+            return PromiseOrValue::Value(U128(amount));
+        }
+        settle_storage(&account_id, initial_storage, env::attached_deposit());
+        PromiseOrValue::Promise(
+            ext_ft::ft_transfer(
+                account_id.clone(),
+                U128(amount),
+                None,
+                reward_token_id,
+                1,
+                GAS_FOR_FT_TRANSFER,
+            )
+            .then(ext_self::claim_rewards_callback(
+                farm_id,
+                account_id,
+                U128(amount),
+                env::current_account_id(),
+                0,
+                GAS_FOR_CALLBACK,
+            )),
+        )
+    }
+
+    #[private]
+    pub fn claim_rewards_callback(
+        &mut self,
+        farm_id: u64,
+        account_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => amount,
+            PromiseResult::Failed => {
+                // Keep the rewards that could not be sent claimable
+                let mut farmer = self.farmers.get(&account_id).unwrap_or_default();
+                farmer.rewards.entry(farm_id).or_default().unclaimed += amount.0;
+                self.farmers.insert(&account_id, &farmer);
+                log!(
+                    "Claim of {} rewards of farm {} by {} failed",
+                    amount.0,
+                    farm_id,
+                    account_id
+                );
+                U128(0)
+            }
+        }
+    }
+
+    /// Gives the owner the reward an ended farm could not pay out, because
+    /// nobody was staking. Returns the amount.
+    pub fn withdraw_farm_leftover(&mut self, farm_id: u64) -> PromiseOrValue<U128> {
+        self.assert_owner();
+        let total_staked = self.total_staked();
+        let mut farm = self.farms.get(farm_id).expect("Farm not found");
+        let now = env::block_timestamp();
+        if now < farm.end_at {
+            panic!("The farm has not ended");
+        }
+        farm.update(total_staked, now);
+        let amount = farm.undistributed;
+        farm.undistributed = 0;
+        self.farms.replace(farm_id, &farm);
+        if amount == 0 {
+            return PromiseOrValue::Value(U128(0));
+        }
+
+        let owner_id = self.owner_id.clone();
+        if self.is_pool_token(&farm.reward_token_id) {
+            let token = self.get_token_by_name_as_ref(&farm.reward_token_id);
+            register_account(&mut token.0, &owner_id);
+            token
+                .0
+                .internal_transfer(&farming_account(), &owner_id, amount, None);
+            return PromiseOrValue::Value(U128(amount));
+        }
+        PromiseOrValue::Promise(
+            ext_ft::ft_transfer(
+                owner_id,
+                U128(amount),
+                None,
+                farm.reward_token_id,
+                1,
+                GAS_FOR_FT_TRANSFER,
+            )
+            .then(ext_self::withdraw_farm_leftover_callback(
+                farm_id,
+                U128(amount),
+                env::current_account_id(),
+                0,
+                GAS_FOR_CALLBACK,
+            )),
+        )
+    }
+
+    #[private]
+    pub fn withdraw_farm_leftover_callback(&mut self, farm_id: u64, amount: U128) -> U128 {
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => amount,
+            PromiseResult::Failed => {
+                // Keep the reward that could not be sent in the farm
+                let mut farm = self.farms.get(farm_id).unwrap();
+                farm.undistributed += amount.0;
+                self.farms.replace(farm_id, &farm);
+                log!("Withdraw of {} leftover of farm {} failed", amount.0, farm_id);
+                U128(0)
+            }
+        }
+    }
+
+    pub fn get_farm(&self, farm_id: u64) -> Option<String> {
+        self.farms
+            .get(farm_id)
+            .map(|farm| farm_json(farm_id, &farm).to_string())
+    }
+
+    pub fn get_farms(&self, from_index: u64, limit: u64) -> String {
+        let farms: Vec<_> = (from_index..self.farms.len().min(from_index.saturating_add(limit)))
+            .map(|farm_id| farm_json(farm_id, &self.farms.get(farm_id).unwrap()))
+            .collect();
+        json!(farms).to_string()
+    }
+
+    pub fn get_staked_shares(&self, account_id: AccountId) -> U128 {
+        U128(
+            self.farmers
+                .get(&account_id)
+                .map_or(0, |farmer| farmer.staked),
+        )
+    }
+
+    /// Rewards of the farm the account could claim right now.
+    pub fn get_unclaimed_rewards(&self, farm_id: u64, account_id: AccountId) -> U128 {
+        let mut farm = self.farms.get(farm_id).expect("Farm not found");
+        let farmer = match self.farmers.get(&account_id) {
+            Some(farmer) => farmer,
+            None => return U128(0),
+        };
+        farm.update(self.total_staked(), env::block_timestamp());
+        let reward = farmer.rewards.get(&farm_id).cloned().unwrap_or_default();
+        U128(reward.unclaimed + farm.accrued(farmer.staked) - reward.debt)
+    }
+
+    /// Adds the tokens sent with `ft_transfer_call` to the farm reward.
+    pub(crate) fn fund_farm(&mut self, farm_id: u64, token_name: &AccountId, amount: Balance) {
+        let total_staked = self.total_staked();
+        let mut farm = self.farms.get(farm_id).expect("Farm not found");
+        if !farm.reward_token_id.eq(token_name) {
+            panic!("The farm pays in another token");
+        }
+        let now = env::block_timestamp();
+        if now >= farm.end_at {
+            panic!("The farm has ended");
+        }
+        farm.update(total_staked, now);
+        farm.undistributed += amount;
+        self.farms.replace(farm_id, &farm);
+        if self.is_pool_token(token_name) {
+            let token = self.get_token_by_name_as_ref(token_name);
+            token.0.internal_deposit(&farming_account(), amount);
+        }
+    }
+
+    /// Brings every farm up to date and settles the rewards of the account
+    /// before its stake changes to `staked`.
+    fn update_farmer(&mut self, account_id: &AccountId, staked: Balance) {
+        let total_staked = self.total_staked();
+        let now = env::block_timestamp();
+        let mut farmer = self.farmers.get(account_id).unwrap_or_default();
+        for farm_id in 0..self.farms.len() {
+            let mut farm = self.farms.get(farm_id).unwrap();
+            farm.update(total_staked, now);
+            let reward = farmer.rewards.entry(farm_id).or_default();
+            reward.unclaimed += farm.accrued(farmer.staked) - reward.debt;
+            reward.debt = farm.accrued(staked);
+            self.farms.replace(farm_id, &farm);
+        }
+        farmer.staked = staked;
+        self.save_farmer(account_id, &farmer);
+    }
+
+    /// Stores the farmer, or drops it once it has nothing staked or to claim.
+    fn save_farmer(&mut self, account_id: &AccountId, farmer: &Farmer) {
+        if farmer.staked == 0 && farmer.rewards.values().all(|reward| reward.unclaimed == 0) {
+            self.farmers.remove(account_id);
+        } else {
+            self.farmers.insert(account_id, farmer);
+        }
+    }
+
+    fn total_staked(&self) -> Balance {
+        self.token_amm.accounts.get(&farming_account()).unwrap_or(0)
+    }
+
+    fn is_pool_token(&self, token_name: &AccountId) -> bool {
+        self.account_id_token_a.eq(token_name) || self.account_id_token_b.eq(token_name)
+    }
+}
+
+fn farm_json(farm_id: u64, farm: &Farm) -> Value {
+    json!({
+        "farm_id": farm_id,
+        "reward_token_id": farm.reward_token_id,
+        "start_at": farm.start_at,
+        "end_at": farm.end_at,
+        "undistributed": U128(farm.undistributed),
+    })
+}
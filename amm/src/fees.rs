@@ -0,0 +1,88 @@
+//! Swap fee configuration and the protocol's cut of it.
+//!
+//! The swap fee stays in the pool for the LPs, except for `protocol_fee`
+//! basis points of it that are set aside in an internal account until they
+//! are claimed to the treasury.
+
+use near_contract_standards::fungible_token::FungibleToken;
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+use near_sdk::{env, near_bindgen, AccountId, Balance};
+
+use crate::*;
+
+pub const DEFAULT_SWAP_FEE: u32 = 30;
+/// Hard maximum of the swap fee, in basis points of the sold amount.
+pub const MAX_SWAP_FEE: u32 = 1_000;
+/// Hard maximum of the protocol fee, in basis points of the swap fee.
+pub const MAX_PROTOCOL_FEE: u32 = 5_000;
+
+#[near_bindgen]
+impl AMM {
+    pub fn set_swap_fee(&mut self, fee: u32) {
+        self.assert_owner();
+        assert_valid_swap_fee(fee);
+        self.swap_fee = fee;
+    }
+
+    pub fn set_protocol_fee(&mut self, fee: u32) {
+        self.assert_owner();
+        if fee > MAX_PROTOCOL_FEE {
+            panic!("The protocol fee can't be more than {}", MAX_PROTOCOL_FEE);
+        }
+        if fee > 0 && self.treasury_id.is_none() {
+            panic!("Please set the treasury first");
+        }
+        self.protocol_fee = fee;
+    }
+
+    pub fn set_treasury(&mut self, treasury_id: AccountId) {
+        self.assert_owner();
+        register_account(&mut self.token_a.0, &treasury_id);
+        register_account(&mut self.token_b.0, &treasury_id);
+        self.index_account(&treasury_id);
+        self.treasury_id = Some(treasury_id);
+    }
+
+    /// Moves the accrued protocol fees to the internal balances of the
+    /// treasury, returns the claimed amounts of token A and B.
+    pub fn claim_protocol_fees(&mut self) -> (U128, U128) {
+        let treasury_id = self.treasury_id.clone().expect("There is no treasury");
+        let predecessor_account_id = env::predecessor_account_id();
+        if predecessor_account_id != self.owner_id && predecessor_account_id != treasury_id {
+            panic!("Only the owner or the treasury can claim protocol fees");
+        }
+
+        let protocol_fees_id = protocol_fees_account();
+        let claimed_a = transfer_all(&mut self.token_a.0, &protocol_fees_id, &treasury_id);
+        let claimed_b = transfer_all(&mut self.token_b.0, &protocol_fees_id, &treasury_id);
+        (U128(claimed_a), U128(claimed_b))
+    }
+
+    pub fn get_fees(&self) -> String {
+        let protocol_fees_id = protocol_fees_account();
+        json!({
+            "swap_fee": self.swap_fee,
+            "protocol_fee": self.protocol_fee,
+            "treasury_id": self.treasury_id,
+            "flash_loan_fee": self.flash_loan_fee,
+            "protocol_fees_a": U128(self.token_a.0.accounts.get(&protocol_fees_id).unwrap_or(0)),
+            "protocol_fees_b": U128(self.token_b.0.accounts.get(&protocol_fees_id).unwrap_or(0)),
+        })
+        .to_string()
+    }
+}
+
+pub(crate) fn assert_valid_swap_fee(fee: u32) {
+    if fee > MAX_SWAP_FEE {
+        panic!("The swap fee can't be more than {}", MAX_SWAP_FEE);
+    }
+}
+
+fn transfer_all(token: &mut FungibleToken, from: &AccountId, to: &AccountId) -> Balance {
+    let amount = token.accounts.get(from).unwrap_or(0);
+    if amount > 0 {
+        token.internal_transfer(from, to, amount, None);
+    }
+    amount
+}
@@ -0,0 +1,266 @@
+//! Collateralised loans of the pool reserves.
+//!
+//! These are not flash loans: they give the borrower no capital it doesn't
+//! already hold. Receipts can't be rolled back across contracts, so a loan
+//! that isn't repaid can't be undone and the borrower has to hold
+//! `amount + fee` on its internal balance, which is escrowed while the loan
+//! is open.
+//!
+//! The borrowed tokens are sent to the receiver, which gets an
+//! `on_flash_loan` call and has to send back `amount + fee` with
+//! `ft_transfer_call` and the `{"repay_flash_loan": {}}` msg before that call
+//! ends. When the loan is not repaid the shortfall is taken from
+//! the escrow and the call ends in a failed receipt, so the pool always gets
+//! back what it lent plus the fee. The pool is locked for swaps and liquidity
+//! changes while a loan is open; should a callback fail and leave the lock
+//! behind, the owner can settle the loan with `clear_flash_loan` once
+//! `FLASH_LOAN_TIMEOUT` blocks have passed.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::{env, ext_contract, log, near_bindgen, AccountId, Balance, Gas, PromiseOrValue};
+
+use crate::*;
+
+pub const DEFAULT_FLASH_LOAN_FEE: u32 = 9;
+/// Hard maximum of the flash loan fee, in basis points of the borrowed amount.
+pub const MAX_FLASH_LOAN_FEE: u32 = 1_000;
+
+const GAS_FOR_FLASH_LOAN: Gas = Gas(30_000_000_000_000);
+const GAS_FOR_FLASH_LOAN_CALLBACK: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_RESOLVE_FLASH_LOAN: Gas = Gas(20_000_000_000_000);
+
+/// Blocks after which the promise chain of a loan is surely over.
+pub const FLASH_LOAN_TIMEOUT: u64 = 1_000;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct FlashLoan {
+    pub token_name: AccountId,
+    pub borrower_id: AccountId,
+    pub amount: Balance,
+    pub fee: Balance,
+    pub repaid: Balance,
+    /// Block height at which the loan was opened.
+    pub opened_at: u64,
+}
+
+fn flash_loans_account() -> AccountId {
+    internal_account("flash-loans")
+}
+
+/// Interface the receiver of a flash loan has to implement.
+#[ext_contract(ext_flash_loan_receiver)]
+pub trait FlashLoanReceiver {
+    fn on_flash_loan(
+        &mut self,
+        sender_id: AccountId,
+        token_name: AccountId,
+        amount: U128,
+        fee: U128,
+        msg: String,
+    );
+}
+
+#[near_bindgen]
+impl AMM {
+    pub fn set_flash_loan_fee(&mut self, fee: u32) {
+        self.assert_owner();
+        if fee > MAX_FLASH_LOAN_FEE {
+            panic!("The flash loan fee can't be more than {}", MAX_FLASH_LOAN_FEE);
+        }
+        self.flash_loan_fee = fee;
+    }
+
+    /// Lends `amount` of the pool reserve to `receiver_id`, with all the gas
+    /// left after this contract's own calls. This is a collateralised loan:
+    /// `amount + fee` is escrowed from the internal balance of the caller
+    /// until the loan is settled.
+    pub fn flash_loan(
+        &mut self,
+        token_name: AccountId,
+        amount: U128,
+        receiver_id: AccountId,
+        msg: String,
+    ) -> Promise {
+        self.assert_no_flash_loan();
+        if self.is_measured(&token_name) {
+            panic!("Flash loans of measured tokens are not supported");
+        }
+        if is_native(&token_name) {
+            panic!("Flash loans of NEAR are not supported");
+        }
+        let reserved_gas = GAS_FOR_FLASH_LOAN
+            + GAS_FOR_FT_TRANSFER
+            + GAS_FOR_FLASH_LOAN_CALLBACK
+            + GAS_FOR_RESOLVE_FLASH_LOAN;
+        if env::prepaid_gas() < reserved_gas + env::used_gas() {
+            panic!("Not enough gas attached");
+        }
+        let receiver_gas = env::prepaid_gas() - env::used_gas() - reserved_gas;
+
+        let borrower_id = env::predecessor_account_id();
+        let fee = calc_fee(amount.0, self.flash_loan_fee);
+        let escrow_id = flash_loans_account();
+        let token = self.get_token_by_name_as_ref(&token_name);
+        register_account(&mut token.0, &escrow_id);
+        token
+            .0
+            .internal_transfer(&borrower_id, &escrow_id, amount.0 + fee, None);
+        token.0.internal_withdraw(&env::current_account_id(), amount.0);
+        self.flash_loan = Some(FlashLoan {
+            token_name: token_name.clone(),
+            borrower_id,
+            amount: amount.0,
+            fee,
+            repaid: 0,
+            opened_at: env::block_height(),
+        });
+
+        ext_ft::ft_transfer(
+            receiver_id.clone(),
+            amount,
+            None,
+            token_name,
+            1,
+            GAS_FOR_FT_TRANSFER,
+        )
+        .then(ext_self::flash_loan_callback(
+            receiver_id,
+            msg,
+            receiver_gas,
+            env::current_account_id(),
+            0,
+            GAS_FOR_FLASH_LOAN_CALLBACK + receiver_gas + GAS_FOR_RESOLVE_FLASH_LOAN,
+        ))
+    }
+
+    #[private]
+    pub fn flash_loan_callback(
+        &mut self,
+        receiver_id: AccountId,
+        msg: String,
+        receiver_gas: Gas,
+    ) -> Promise {
+        let loan = self.flash_loan.as_ref().unwrap();
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => ext_flash_loan_receiver::on_flash_loan(
+                loan.borrower_id.clone(),
+                loan.token_name.clone(),
+                U128(loan.amount),
+                U128(loan.fee),
+                msg,
+                receiver_id,
+                0,
+                receiver_gas,
+            )
+            .then(ext_self::resolve_flash_loan(
+                env::current_account_id(),
+                0,
+                GAS_FOR_RESOLVE_FLASH_LOAN,
+            )),
+            PromiseResult::Failed => {
+                // The tokens never left, put them back into the pool
+                let loan = self.flash_loan.take().unwrap();
+                let escrow_id = flash_loans_account();
+                let token = self.get_token_by_name_as_ref(&loan.token_name);
+                token.0.internal_deposit(&env::current_account_id(), loan.amount);
+                token.0.internal_transfer(
+                    &escrow_id,
+                    &loan.borrower_id,
+                    loan.amount + loan.fee,
+                    None,
+                );
+                fail_flash_loan("Flash loan transfer failed")
+            }
+        }
+    }
+
+    #[private]
+    pub fn resolve_flash_loan(&mut self) -> PromiseOrValue<bool> {
+        let loan = self.flash_loan.take().expect("There is no flash loan");
+        if self.settle_flash_loan(loan) {
+            PromiseOrValue::Value(true)
+        } else {
+            PromiseOrValue::Promise(fail_flash_loan("Flash loan was not repaid"))
+        }
+    }
+
+    /// Settles a loan whose promise chain didn't end, as if it wasn't repaid
+    /// past what was sent back so far.
+    pub fn clear_flash_loan(&mut self) {
+        self.assert_owner();
+        let loan = self.flash_loan.as_ref().expect("There is no flash loan");
+        if env::block_height() < loan.opened_at + FLASH_LOAN_TIMEOUT {
+            panic!(
+                "The flash loan can't be cleared before block {}",
+                loan.opened_at + FLASH_LOAN_TIMEOUT
+            );
+        }
+        let loan = self.flash_loan.take().unwrap();
+        self.settle_flash_loan(loan);
+    }
+
+    #[private]
+    pub fn flash_loan_failed(&mut self, reason: String) {
+        panic!("{}", reason);
+    }
+
+    /// Puts tokens sent back with `ft_transfer_call` into the pool.
+    pub(crate) fn repay_flash_loan(&mut self, token_name: &AccountId, amount: Balance) {
+        match self.flash_loan.as_mut() {
+            Some(loan) if loan.token_name.eq(token_name) => loan.repaid += amount,
+            _ => panic!("There is no flash loan of this token to repay"),
+        }
+        let token = self.get_token_by_name_as_ref(token_name);
+        token.0.internal_deposit(&env::current_account_id(), amount);
+    }
+
+    /// Takes what wasn't repaid out of the escrow, gives the rest back to the
+    /// borrower. Returns whether the loan was repaid in full.
+    fn settle_flash_loan(&mut self, loan: FlashLoan) -> bool {
+        let owed = loan.amount + loan.fee;
+        let shortfall = owed.saturating_sub(loan.repaid);
+        let escrow_id = flash_loans_account();
+        let pool_owner_id = env::current_account_id();
+        let token = self.get_token_by_name_as_ref(&loan.token_name);
+        if shortfall > 0 {
+            token
+                .0
+                .internal_transfer(&escrow_id, &pool_owner_id, shortfall, None);
+        }
+        if owed > shortfall {
+            token
+                .0
+                .internal_transfer(&escrow_id, &loan.borrower_id, owed - shortfall, None);
+        }
+        self.accrue_lp_fee(&loan.token_name, loan.fee);
+        if shortfall > 0 {
+            log!(
+                "Flash loan of {} {} was not repaid, took the {} missing from the escrow of {}",
+                loan.amount,
+                loan.token_name,
+                shortfall,
+                loan.borrower_id
+            );
+        }
+        shortfall == 0
+    }
+
+    pub(crate) fn assert_no_flash_loan(&self) {
+        if self.flash_loan.is_some() {
+            panic!("The pool is locked by a flash loan");
+        }
+    }
+}
+
+/// Ends the promise chain in a failed receipt, keeping the state changes of
+/// the current one.
+fn fail_flash_loan(reason: &str) -> Promise {
+    ext_self::flash_loan_failed(
+        reason.to_string(),
+        env::current_account_id(),
+        0,
+        GAS_FOR_CALLBACK,
+    )
+}
@@ -8,58 +8,321 @@ use near_contract_standards::storage_management::{
     StorageBalance, StorageBalanceBounds, StorageManagement,
 };
 use near_sdk::{
-    env, log, near_bindgen, AccountId, Gas, PanicOnDefault, PromiseOrValue, PromiseResult,
+    env, log, near_bindgen, AccountId, Balance, Gas, PanicOnDefault, Promise, PromiseOrValue,
+    PromiseResult, StorageUsage,
 };
 
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet, Vector};
 use near_sdk::ext_contract;
 use near_sdk::json_types::U128;
-use near_sdk::serde_json::json;
+use near_sdk::serde::Deserialize;
+use near_sdk::serde_json::{self, json};
 
+mod accounts;
+mod actions;
+mod dca;
+mod farming;
+mod fees;
+// The generated `ext_flash_loan_receiver::on_flash_loan` takes eight arguments
+#[allow(clippy::too_many_arguments)]
+mod flash_loan;
+mod locks;
+mod measured;
+mod migration;
+mod native;
+mod orders;
+mod positions;
+mod referral;
+mod reserves;
+mod stats;
+mod upgrade;
 mod utils;
-use utils::{add_decimals, calc_dy, remove_decimals, calc_raito};
+use actions::Action;
+use dca::Schedule;
+use farming::{Farm, Farmer};
+use fees::{assert_valid_swap_fee, DEFAULT_SWAP_FEE};
+use flash_loan::{FlashLoan, DEFAULT_FLASH_LOAN_FEE};
+use locks::Lock;
+use measured::PendingDeposit;
+use migration::StateVersion;
+use native::is_native;
+use orders::{LimitOrder, DEFAULT_KEEPER_FEE};
+use positions::LpFees;
+use referral::Referrer;
+use stats::SwapStats;
+use upgrade::{StagedUpgrade, DEFAULT_UPGRADE_DELAY};
+use utils::{
+    add_decimals, calc_burn_shares, calc_dy, calc_fee, calc_fee_cut, calc_raito, calc_shares,
+};
+
+/// Action run on the tokens deposited with `ft_transfer_call`.
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum TokenReceiverMessage {
+    /// Sells all the deposited tokens for `buy_token_name`.
+    Swap {
+        buy_token_name: AccountId,
+        referral_id: Option<AccountId>,
+    },
+    /// Pays back the open flash loan of the deposited token.
+    RepayFlashLoan {},
+    /// Adds the deposited tokens to the reward of the farm.
+    FundFarm { farm_id: u64 },
+    /// Runs the actions against the balances of the sender once the tokens
+    /// are deposited, see `execute_actions`.
+    Execute { actions: Vec<Action> },
+}
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct AMM {
+    /// Leading tag of the stored state, see `migration`.
+    state_version: StateVersion,
     pub token_amm: FungibleToken,
     pub token_a: (FungibleToken, Option<FungibleTokenMetadata>),
     pub token_b: (FungibleToken, Option<FungibleTokenMetadata>),
     account_id_token_a: AccountId,
     account_id_token_b: AccountId,
+    owner_id: AccountId,
+    swap_fee: u32,
+    protocol_fee: u32,
+    treasury_id: Option<AccountId>,
+    referral_fee: u32,
+    referrers: UnorderedMap<AccountId, Referrer>,
+    flash_loan_fee: u32,
+    flash_loan: Option<FlashLoan>,
+    orders: UnorderedMap<u64, LimitOrder>,
+    next_order_id: u64,
+    /// Ids of the orders of every account, see `orders`.
+    account_orders: AccountIndex,
+    keeper_fee: u32,
+    schedules: UnorderedMap<u64, Schedule>,
+    next_schedule_id: u64,
+    /// Ids of the schedules of every account, see `dca`.
+    account_schedules: AccountIndex,
+    farms: Vector<Farm>,
+    farmers: UnorderedMap<AccountId, Farmer>,
+    locks: UnorderedMap<u64, Lock>,
+    next_lock_id: u64,
+    /// Ids of the locks of every account, see `locks`.
+    account_locks: AccountIndex,
+    measured_a: bool,
+    measured_b: bool,
+    /// Measured deposit in flight, see `measured`.
+    pending_deposit: Option<PendingDeposit>,
+    /// `ft_transfer`s of withdrawals in flight, per token.
+    withdrawals_in_flight_a: u64,
+    withdrawals_in_flight_b: u64,
+    /// Block height at which a `sync` or `skim` queried the balances, see
+    /// `reserves`.
+    reconciling_since: Option<u64>,
+    upgrade_delay: u64,
+    staged_upgrade: Option<StagedUpgrade>,
+    /// Accounts registered or holding balances, see `accounts`.
+    accounts: UnorderedSet<AccountId>,
+    /// Words of the U256 fees kept by the pool per share, see `positions`.
+    lp_fees_per_share_a: [u64; 4],
+    lp_fees_per_share_b: [u64; 4],
+    lp_fees: LookupMap<AccountId, LpFees>,
+    stats: SwapStats,
 }
 
+/// Shares burned on the first mint, so that the share price can't be
+/// inflated enough to round the shares of later deposits down to zero.
+const MINIMUM_LIQUIDITY: Balance = 1_000;
+
+const GAS_FOR_FT_TRANSFER: Gas = Gas(5_000_000_000_000);
+const GAS_FOR_FT_BALANCE_OF: Gas = Gas(5_000_000_000_000);
+const GAS_FOR_FT_METADATA: Gas = Gas(5_000_000_000_000);
+const GAS_FOR_CALLBACK: Gas = Gas(5_000_000_000_000);
+
 fn init_token(account_id: &AccountId, prefix: Vec<u8>) -> FungibleToken {
     let mut a = FungibleToken::new(prefix);
-    a.internal_register_account(account_id);
+    register_account(&mut a, account_id);
+    register_account(&mut a, &env::current_account_id());
     a
 }
 
+/// Account that holds tokens on behalf of the contract itself. Only this
+/// contract could create the sub-account, so nobody can ever own it.
+fn internal_account(name: &str) -> AccountId {
+    format!("{}.{}", name, env::current_account_id())
+        .parse()
+        .unwrap()
+}
+
+fn protocol_fees_account() -> AccountId {
+    internal_account("protocol-fees")
+}
+
+fn parse_message(msg: &str) -> Option<TokenReceiverMessage> {
+    if msg.is_empty() {
+        None
+    } else {
+        Some(serde_json::from_str(msg).expect("Wrong msg format"))
+    }
+}
+
+fn register_account(token: &mut FungibleToken, account_id: &AccountId) {
+    if !token.accounts.contains_key(account_id) {
+        token.internal_register_account(account_id);
+    }
+}
+
+/// Charges `account_id` for the storage used since `initial_storage` out of
+/// its `deposit`, and refunds the rest or the storage freed.
+fn settle_storage(account_id: &AccountId, initial_storage: StorageUsage, deposit: Balance) {
+    let storage = env::storage_usage();
+    let refund = if storage > initial_storage {
+        let cost = Balance::from(storage - initial_storage) * env::storage_byte_cost();
+        if deposit < cost {
+            panic!("Please attach at least {} yoctoNEAR for the storage", cost);
+        }
+        deposit - cost
+    } else {
+        deposit + Balance::from(initial_storage - storage) * env::storage_byte_cost()
+    };
+    if refund > 0 {
+        Promise::new(account_id.clone()).transfer(refund);
+    }
+}
+
+/// Ids of the orders, schedules or locks of every account, each in its own
+/// set.
+type AccountIndex = LookupMap<AccountId, UnorderedSet<u64>>;
+
+fn index_insert(index: &mut AccountIndex, prefix: &[u8], account_id: &AccountId, id: u64) {
+    let mut ids = index.get(account_id).unwrap_or_else(|| {
+        UnorderedSet::new([prefix, &env::sha256(account_id.as_bytes())].concat())
+    });
+    ids.insert(&id);
+    index.insert(account_id, &ids);
+}
+
+fn index_remove(index: &mut AccountIndex, account_id: &AccountId, id: u64) {
+    let mut ids = index.get(account_id).expect("The id is not indexed");
+    ids.remove(&id);
+    if ids.is_empty() {
+        index.remove(account_id);
+    } else {
+        index.insert(account_id, &ids);
+    }
+}
+
+fn index_get(index: &AccountIndex, account_id: &AccountId) -> Vec<u64> {
+    index.get(account_id).map_or_else(Vec::new, |ids| ids.to_vec())
+}
+
 #[ext_contract(ext_self)]
 trait SelfContract {
-    fn withdraw_tokens_callback(&mut self, token_name: String, amount: U128);
+    fn withdraw_tokens_callback(&mut self, token_name: AccountId, account_id: AccountId, amount: U128);
+    fn sync_callback(&mut self);
+    fn skim_callback(&mut self, to: AccountId);
+    fn audit_reserves_callback(&self) -> String;
+    fn flash_loan_callback(&mut self, receiver_id: AccountId, msg: String, receiver_gas: Gas);
+    fn resolve_flash_loan(&mut self);
+    fn flash_loan_failed(&mut self, reason: String);
+    fn measured_deposit_callback(
+        &mut self,
+        token_name: AccountId,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> U128;
+    fn claim_rewards_callback(&mut self, farm_id: u64, account_id: AccountId, amount: U128) -> U128;
+    fn withdraw_farm_leftover_callback(&mut self, farm_id: u64, amount: U128) -> U128;
+    fn refresh_metadata_callback(&mut self, token_name: AccountId);
 }
 
 #[ext_contract(ext_ft)]
 trait FtContract {
     fn ft_transfer(&self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+    fn ft_balance_of(&self, account_id: AccountId) -> U128;
+    fn ft_metadata(&self) -> FungibleTokenMetadata;
 }
 
 #[near_bindgen]
 impl AMM {
     #[init]
-    pub fn new(owner_id: AccountId, token_a_id: AccountId, token_b_id: AccountId) -> Self {
+    pub fn new(
+        owner_id: AccountId,
+        token_a_id: AccountId,
+        token_b_id: AccountId,
+        swap_fee: Option<u32>,
+    ) -> Self {
         assert!(!env::state_exists(), "Already initialized");
         let ft_a = init_token(&owner_id, b"a".to_vec());
         let ft_b = init_token(&owner_id, b"b".to_vec());
         let token_amm = init_token(&owner_id, b"amm".to_vec());
 
+        let mut amm = Self::from_tokens(
+            token_amm,
+            (ft_a, None),
+            (ft_b, None),
+            token_a_id,
+            token_b_id,
+            owner_id.clone(),
+        );
+        amm.index_account(&owner_id);
+        amm.index_account(&env::current_account_id());
+        if let Some(fee) = swap_fee {
+            assert_valid_swap_fee(fee);
+            amm.swap_fee = fee;
+        }
+        amm
+    }
+
+    /// State around the given tokens, with the default settings and nothing
+    /// else stored yet.
+    fn from_tokens(
+        token_amm: FungibleToken,
+        token_a: (FungibleToken, Option<FungibleTokenMetadata>),
+        token_b: (FungibleToken, Option<FungibleTokenMetadata>),
+        account_id_token_a: AccountId,
+        account_id_token_b: AccountId,
+        owner_id: AccountId,
+    ) -> Self {
         Self {
+            state_version: StateVersion::V2,
             token_amm,
-            token_a: (ft_a, None),
-            token_b: (ft_b, None),
-            account_id_token_a: token_a_id,
-            account_id_token_b: token_b_id,
+            token_a,
+            token_b,
+            account_id_token_a,
+            account_id_token_b,
+            owner_id,
+            swap_fee: DEFAULT_SWAP_FEE,
+            protocol_fee: 0,
+            treasury_id: None,
+            referral_fee: 0,
+            referrers: UnorderedMap::new(b"r".to_vec()),
+            flash_loan_fee: DEFAULT_FLASH_LOAN_FEE,
+            flash_loan: None,
+            orders: UnorderedMap::new(b"o".to_vec()),
+            next_order_id: 0,
+            account_orders: LookupMap::new(b"q".to_vec()),
+            keeper_fee: DEFAULT_KEEPER_FEE,
+            schedules: UnorderedMap::new(b"d".to_vec()),
+            next_schedule_id: 0,
+            account_schedules: LookupMap::new(b"c".to_vec()),
+            farms: Vector::new(b"f".to_vec()),
+            farmers: UnorderedMap::new(b"s".to_vec()),
+            locks: UnorderedMap::new(b"l".to_vec()),
+            next_lock_id: 0,
+            account_locks: LookupMap::new(b"k".to_vec()),
+            measured_a: false,
+            measured_b: false,
+            pending_deposit: None,
+            withdrawals_in_flight_a: 0,
+            withdrawals_in_flight_b: 0,
+            reconciling_since: None,
+            upgrade_delay: DEFAULT_UPGRADE_DELAY,
+            staged_upgrade: None,
+            accounts: UnorderedSet::new(b"i".to_vec()),
+            lp_fees_per_share_a: [0; 4],
+            lp_fees_per_share_b: [0; 4],
+            lp_fees: LookupMap::new(b"e".to_vec()),
+            stats: SwapStats::default(),
         }
     }
 
@@ -78,7 +341,8 @@ impl AMM {
         json!({
             "ticker": ticker,
             "decimals": max(token_a_decimals, token_b_decimals),
-            "ratio": ratio
+            "ratio": ratio,
+            "fee": self.swap_fee
         })
         .to_string()
     }
@@ -139,23 +403,53 @@ impl AMM {
         }
     }
 
+    /// Reloads the metadata of a token from its contract, after the token
+    /// emitted an `ft_metadata_update` event.
+    pub fn refresh_metadata(&mut self, token_name: AccountId) -> Promise {
+        self.check_meta();
+        if is_native(&token_name) {
+            panic!("The metadata of NEAR can't be refreshed");
+        }
+        self.get_token_by_name(&token_name);
+        ext_ft::ft_metadata(token_name.clone(), 0, GAS_FOR_FT_METADATA).then(
+            ext_self::refresh_metadata_callback(
+                token_name,
+                env::current_account_id(),
+                0,
+                GAS_FOR_CALLBACK,
+            ),
+        )
+    }
+
+    #[private]
+    pub fn refresh_metadata_callback(&mut self, token_name: AccountId) {
+        let meta = match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Failed => panic!("Failed to read the token metadata"),
+            PromiseResult::Successful(value) => {
+                serde_json::from_slice::<FungibleTokenMetadata>(&value).unwrap()
+            }
+        };
+        let token = self.get_token_by_name_as_ref(&token_name);
+        // The reserves and shares are computed with the decimals
+        if token.1.as_ref().unwrap().decimals != meta.decimals {
+            panic!("The decimals of a token can't change");
+        }
+        token.1 = Some(meta);
+    }
+
     pub fn exclude_token_from_pool(
         &mut self,
         token_name: AccountId,
         token_amount: U128,
         memo: Option<String>,
     ) {
-        self.check_meta();
-        let token = self.get_token_by_name_as_ref(&token_name);
-        let predecessor_account_id = env::predecessor_account_id();
-        token.0.internal_transfer(
-            &env::current_account_id(),
-            &predecessor_account_id,
+        self.internal_exclude_from_pool(
+            &env::predecessor_account_id(),
+            &token_name,
             token_amount.0,
             memo,
         );
-        self.token_amm
-            .internal_withdraw(&predecessor_account_id, token_amount.0);
     }
 
     pub fn add_token_to_pool(
@@ -164,23 +458,89 @@ impl AMM {
         token_amount: U128,
         memo: Option<String>,
     ) {
+        self.internal_add_to_pool(
+            &env::predecessor_account_id(),
+            &token_name,
+            token_amount.0,
+            memo,
+        );
+    }
+
+    /// Burns the shares worth `token_amount` of `token_name` and gives the
+    /// tokens to `account_id`. Returns the burned shares.
+    fn internal_exclude_from_pool(
+        &mut self,
+        account_id: &AccountId,
+        token_name: &AccountId,
+        token_amount: Balance,
+        memo: Option<String>,
+    ) -> Balance {
         self.check_meta();
-        let token = self.get_token_by_name_as_ref(&token_name);
+        self.assert_no_flash_loan();
+        let shares = calc_burn_shares(
+            self.to_pool_decimals(token_name, token_amount),
+            self.pool_value(),
+            self.token_amm.total_supply,
+        );
+        let token = self.get_token_by_name_as_ref(token_name);
+        token
+            .0
+            .internal_transfer(&env::current_account_id(), account_id, token_amount, memo);
+        self.settle_lp_fees(account_id);
+        self.token_amm.internal_withdraw(account_id, shares);
+        shares
+    }
+
+    /// Moves `token_amount` of `token_name` from `payer_id` to the pool and
+    /// mints the shares. Returns the minted shares.
+    fn internal_add_to_pool(
+        &mut self,
+        payer_id: &AccountId,
+        token_name: &AccountId,
+        token_amount: Balance,
+        memo: Option<String>,
+    ) -> Balance {
+        self.check_meta();
+        self.assert_no_flash_loan();
+        let first_mint = self.token_amm.total_supply == 0;
+        let mut shares = calc_shares(
+            self.to_pool_decimals(token_name, token_amount),
+            self.pool_value(),
+            self.token_amm.total_supply,
+        );
+        if shares == 0 {
+            panic!("The deposit is too small to mint any shares");
+        }
+        if first_mint {
+            if shares <= MINIMUM_LIQUIDITY {
+                panic!(
+                    "The first deposit must be worth more than {} shares",
+                    MINIMUM_LIQUIDITY
+                );
+            }
+            let locked_id = internal_account("locked-liquidity");
+            register_account(&mut self.token_amm, &locked_id);
+            self.settle_lp_fees(&locked_id);
+            self.token_amm.internal_deposit(&locked_id, MINIMUM_LIQUIDITY);
+            shares -= MINIMUM_LIQUIDITY;
+        }
+        let token = self.get_token_by_name_as_ref(token_name);
         let pool_owner_id = env::current_account_id();
-        let payer_id = env::predecessor_account_id();
 
         token
             .0
-            .internal_transfer(&payer_id, &pool_owner_id, token_amount.0, memo);
+            .internal_transfer(payer_id, &pool_owner_id, token_amount, memo);
 
         let ticker = token.1.clone().unwrap().symbol;
-        self.token_amm.internal_deposit(&payer_id, token_amount.0);
+        self.settle_lp_fees(payer_id);
+        self.token_amm.internal_deposit(payer_id, shares);
         log!(
             "Share {} of token {} has been added to account {}",
-            token_amount.0,
+            shares,
             ticker,
-            &payer_id
+            payer_id
         );
+        shares
     }
 
     pub fn swap(
@@ -188,100 +548,90 @@ impl AMM {
         buy_token_name: AccountId,
         sell_token_name: AccountId,
         sell_amount: U128,
+        referral_id: Option<AccountId>,
     ) -> U128 {
-        if buy_token_name.eq(&sell_token_name) {
-            panic!("Tokens can't be equals")
-        }
-
-        self.check_meta();
-        let buy_token;
-        let sell_token;
-        if buy_token_name.eq(&self.account_id_token_a)
-            && sell_token_name.eq(&self.account_id_token_b)
-        {
-            buy_token = &mut self.token_a;
-            sell_token = &mut self.token_b;
-        } else if buy_token_name.eq(&self.account_id_token_b)
-            && sell_token_name.eq(&self.account_id_token_a)
-        {
-            buy_token = &mut self.token_b;
-            sell_token = &mut self.token_a;
-        } else {
-            panic!("Token not supported");
-        }
-        let pool_owner_id = env::current_account_id();
-        let user_account_id = env::predecessor_account_id();
-
-        // Get current statement of pool
-        let x = sell_token.0.internal_unwrap_balance_of(&pool_owner_id);
-        let y = buy_token.0.internal_unwrap_balance_of(&pool_owner_id);
-
-        // Send sell_tokens to pool from seller
-        sell_token
-            .0
-            .internal_transfer(&user_account_id, &pool_owner_id, sell_amount.0, None);
-
-        // Convert to the same decimal
-        let max_decimals = max(
-            buy_token.1.clone().unwrap().decimals,
-            sell_token.1.clone().unwrap().decimals,
-        );
-        let x = add_decimals(x, max_decimals - sell_token.1.clone().unwrap().decimals);
-        let y = add_decimals(y, max_decimals - buy_token.1.clone().unwrap().decimals);
-
-        // Calc buy amount
-        let buy_amount = calc_dy(x, y, sell_amount.0);
-
-        // Restore decimal
-        let buy_amount = remove_decimals(
-            buy_amount,
-            max_decimals - buy_token.1.clone().unwrap().decimals,
+        let buy_amount = self.internal_swap(
+            &env::predecessor_account_id(),
+            &buy_token_name,
+            &sell_token_name,
+            sell_amount.0,
+            referral_id.as_ref(),
         );
-
-        // Send buy value to user buyer
-        buy_token
-            .0
-            .internal_transfer(&pool_owner_id, &user_account_id, buy_amount, None);
-
         U128::from(buy_amount)
     }
 
+    /// Amount of `buy_token_name` that selling `sell_amount` would return now.
+    pub fn get_return(
+        &self,
+        buy_token_name: AccountId,
+        sell_token_name: AccountId,
+        sell_amount: U128,
+    ) -> U128 {
+        U128(self.quote_swap(&buy_token_name, &sell_token_name, sell_amount.0))
+    }
+
     #[payable]
-    pub fn withdraw_tokens(&mut self, token_name: AccountId, amount: U128) {
-        let account_id = env::predecessor_account_id();
+    pub fn withdraw_tokens(&mut self, token_name: AccountId, amount: U128) -> Promise {
+        self.internal_withdraw_tokens(env::predecessor_account_id(), token_name, amount)
+    }
+
+    /// Sends `amount` of `token_name` from the internal balance of
+    /// `account_id` to its account on the token contract.
+    fn internal_withdraw_tokens(
+        &mut self,
+        account_id: AccountId,
+        token_name: AccountId,
+        amount: U128,
+    ) -> Promise {
         if !self.account_id_token_a.eq(&token_name) && !self.account_id_token_b.eq(&token_name) {
             panic!("Token not supported");
         }
-        ext_ft::ft_transfer(
+
+        // Clear the tokens before sending them, the callback returns them on failure
+        let token = self.get_token_by_name_as_ref(&token_name);
+        token.0.internal_withdraw(&account_id, amount.0);
+
+        let transfer = if is_native(&token_name) {
+            Promise::new(account_id.clone()).transfer(amount.0)
+        } else {
+            self.start_withdrawal(&token_name);
+            ext_ft::ft_transfer(
+                account_id.clone(),
+                amount,
+                None,
+                token_name.clone(),
+                1,
+                GAS_FOR_FT_TRANSFER,
+            )
+        };
+        transfer.then(ext_self::withdraw_tokens_callback(
+            token_name,
             account_id,
             amount,
-            None,
-            token_name.clone(),
-            1,
-            Gas::from(5_000_000_000_000),
-        )
-        .then(ext_self::withdraw_tokens_callback(
-            token_name.to_string(),
-            amount,
             env::current_account_id(),
             0,
-            Gas::from(5_000_000_000_000),
-        ));
+            GAS_FOR_CALLBACK,
+        ))
     }
 
-    pub fn withdraw_tokens_callback(&mut self, token_name: AccountId, amount: U128) {
+    #[private]
+    pub fn withdraw_tokens_callback(
+        &mut self,
+        token_name: AccountId,
+        account_id: AccountId,
+        amount: U128,
+    ) {
+        if !is_native(&token_name) {
+            self.end_withdrawal(&token_name);
+        }
         match env::promise_result(0) {
             PromiseResult::NotReady => unreachable!(),
-            PromiseResult::Failed => "error!".to_string(),
-            PromiseResult::Successful(_) => {
-                // Get the user who sent the tokens
-                let account_id = env::signer_account_id();
+            PromiseResult::Successful(_) => {}
+            PromiseResult::Failed => {
+                // Give back the tokens that could not be sent
                 let token = self.get_token_by_name_as_ref(&token_name);
-
-                // Clear sent tokens value
-                token.0.internal_withdraw(&account_id, amount.0);
-
-                "Ok".to_string()
+                token.0.internal_deposit(&account_id, amount.0);
+                log!("Withdraw of {} {} to {} failed", amount.0, token_name, account_id);
             }
         };
     }
@@ -306,6 +656,7 @@ impl AMM {
         account_id: AccountId,
         registration_only: Option<bool>,
     ) {
+        self.index_account(&account_id);
         if token_name == env::current_account_id() {
             self.token_amm
                 .storage_deposit(Some(account_id), registration_only);
@@ -329,15 +680,15 @@ impl AMM {
     #[payable]
     #[allow(dead_code)]
     fn storage_unregister(&mut self, token_name: AccountId, force: Option<bool>) -> bool {
-        if token_name == env::current_account_id() {
-            if let Some((_, _)) = self.token_amm.internal_storage_unregister(force) {
-                return true;
-            }
+        let unregistered = if token_name == env::current_account_id() {
+            self.token_amm.internal_storage_unregister(force)
         } else {
             let token = self.get_token_by_name_as_ref(&token_name);
-            if let Some((_, _)) = token.0.internal_storage_unregister(force) {
-                return true;
-            }
+            token.0.internal_storage_unregister(force)
+        };
+        if unregistered.is_some() {
+            self.unindex_account(&env::predecessor_account_id());
+            return true;
         }
         false
     }
@@ -366,6 +717,151 @@ impl AMM {
         }
     }
 
+    /// Credits a deposit of `amount` to `sender_id` and runs the `message`.
+    fn internal_on_transfer(
+        &mut self,
+        token_name: &AccountId,
+        sender_id: &AccountId,
+        amount: Balance,
+        message: Option<TokenReceiverMessage>,
+    ) {
+        if let Some(TokenReceiverMessage::RepayFlashLoan {}) = message {
+            self.repay_flash_loan(token_name, amount);
+            return;
+        }
+        if let Some(TokenReceiverMessage::FundFarm { farm_id }) = message {
+            self.fund_farm(farm_id, token_name, amount);
+            return;
+        }
+
+        self.index_account(sender_id);
+        let token = self.get_token_by_name_as_ref(token_name);
+        token.0.internal_deposit(sender_id, amount);
+        match message {
+            Some(TokenReceiverMessage::Swap {
+                buy_token_name,
+                referral_id,
+            }) => {
+                self.internal_swap(
+                    sender_id,
+                    &buy_token_name,
+                    token_name,
+                    amount,
+                    referral_id.as_ref(),
+                );
+            }
+            Some(TokenReceiverMessage::Execute { actions }) => {
+                self.internal_execute_actions(sender_id, actions);
+            }
+            _ => {}
+        }
+    }
+
+    fn quote_swap(
+        &self,
+        buy_token_name: &AccountId,
+        sell_token_name: &AccountId,
+        sell_amount: Balance,
+    ) -> Balance {
+        if buy_token_name.eq(sell_token_name) {
+            panic!("Tokens can't be equals")
+        }
+        self.check_meta();
+        let pool_owner_id = env::current_account_id();
+        let sell_token = self.get_token_by_name(sell_token_name);
+        let buy_token = self.get_token_by_name(buy_token_name);
+        calc_dy(
+            sell_token.0.internal_unwrap_balance_of(&pool_owner_id),
+            buy_token.0.internal_unwrap_balance_of(&pool_owner_id),
+            sell_amount - calc_fee(sell_amount, self.swap_fee),
+        )
+    }
+
+    fn internal_swap(
+        &mut self,
+        user_account_id: &AccountId,
+        buy_token_name: &AccountId,
+        sell_token_name: &AccountId,
+        sell_amount: Balance,
+        referral_id: Option<&AccountId>,
+    ) -> Balance {
+        if buy_token_name.eq(sell_token_name) {
+            panic!("Tokens can't be equals")
+        }
+
+        self.check_meta();
+        self.assert_no_flash_loan();
+        // Take the swap fee, part of which is set aside for the protocol and
+        // for a whitelisted referrer
+        let fee = calc_fee(sell_amount, self.swap_fee);
+        let protocol_fee = calc_fee_cut(fee, self.protocol_fee);
+        let protocol_fees_id = protocol_fees_account();
+        let referral = referral_id
+            .filter(|referral_id| self.referrers.get(referral_id).is_some())
+            .map(|referral_id| (referral_id, calc_fee_cut(fee, self.referral_fee)))
+            .filter(|(_, referral_fee)| *referral_fee > 0);
+        let referral_fee = referral.map_or(0, |(_, referral_fee)| referral_fee);
+        let sells_token_a = sell_token_name.eq(&self.account_id_token_a);
+
+        let buy_token;
+        let sell_token;
+        if buy_token_name.eq(&self.account_id_token_a)
+            && sell_token_name.eq(&self.account_id_token_b)
+        {
+            buy_token = &mut self.token_a;
+            sell_token = &mut self.token_b;
+        } else if buy_token_name.eq(&self.account_id_token_b)
+            && sell_token_name.eq(&self.account_id_token_a)
+        {
+            buy_token = &mut self.token_b;
+            sell_token = &mut self.token_a;
+        } else {
+            panic!("Token not supported");
+        }
+        let pool_owner_id = env::current_account_id();
+
+        // Get current statement of pool
+        let x = sell_token.0.internal_unwrap_balance_of(&pool_owner_id);
+        let y = buy_token.0.internal_unwrap_balance_of(&pool_owner_id);
+
+        // Send sell_tokens to pool from seller
+        sell_token.0.internal_transfer(
+            user_account_id,
+            &pool_owner_id,
+            sell_amount - protocol_fee - referral_fee,
+            None,
+        );
+        if protocol_fee > 0 {
+            register_account(&mut sell_token.0, &protocol_fees_id);
+            sell_token
+                .0
+                .internal_transfer(user_account_id, &protocol_fees_id, protocol_fee, None);
+        }
+        if let Some((referral_id, referral_fee)) = referral {
+            sell_token
+                .0
+                .internal_transfer(user_account_id, referral_id, referral_fee, None);
+        }
+
+        // Calc buy amount
+        let buy_amount = calc_dy(x, y, sell_amount - fee);
+        if buy_amount == 0 {
+            panic!("Sell amount is too small");
+        }
+
+        // Send buy value to user buyer
+        buy_token
+            .0
+            .internal_transfer(&pool_owner_id, user_account_id, buy_amount, None);
+
+        if let Some((referral_id, referral_fee)) = referral {
+            self.record_referral_fee(referral_id, sells_token_a, referral_fee);
+        }
+        self.accrue_lp_fee(sell_token_name, fee - protocol_fee - referral_fee);
+        self.record_swap(sells_token_a, sell_amount, buy_amount, fee);
+        buy_amount
+    }
+
     fn token_a(&self) -> Option<&FungibleTokenMetadata> {
         self.token_a.1.as_ref()
     }
@@ -374,12 +870,36 @@ impl AMM {
         self.token_b.1.as_ref()
     }
 
+    fn assert_owner(&self) {
+        if env::predecessor_account_id() != self.owner_id {
+            panic!("Only the owner can call this method");
+        }
+    }
+
     fn check_meta(&self) {
         if self.token_a().is_none() || self.token_b().is_none() {
             panic!("Please init the metadata of tokens")
         }
     }
 
+    /// Value of the pool reserves, with both tokens in the larger decimals.
+    fn pool_value(&self) -> Balance {
+        let pool_owner_id = env::current_account_id();
+        let a = self.token_a.0.internal_unwrap_balance_of(&pool_owner_id);
+        let b = self.token_b.0.internal_unwrap_balance_of(&pool_owner_id);
+        self.to_pool_decimals(&self.account_id_token_a, a)
+            + self.to_pool_decimals(&self.account_id_token_b, b)
+    }
+
+    fn to_pool_decimals(&self, token_name: &AccountId, amount: Balance) -> Balance {
+        let decimals = self.get_token_by_name(token_name).1.as_ref().unwrap().decimals;
+        let max_decimals = max(
+            self.token_a().unwrap().decimals,
+            self.token_b().unwrap().decimals,
+        );
+        add_decimals(amount, max_decimals - decimals)
+    }
+
     fn get_token_by_name_as_ref(
         &mut self,
         token: &AccountId,
@@ -409,15 +929,25 @@ impl AMM {
 
 #[near_bindgen]
 impl FungibleTokenReceiver for AMM {
+    /// Deposits the tokens to the internal balance of the sender. A non-empty
+    /// `msg` is a `TokenReceiverMessage` run against that balance afterwards.
     fn ft_on_transfer(
         &mut self,
         sender_id: AccountId,
         amount: U128,
-        #[allow(unused_variables)] msg: String,
+        msg: String,
     ) -> PromiseOrValue<U128> {
         let token_name = &env::predecessor_account_id();
-        let token = self.get_token_by_name_as_ref(token_name);
-        token.0.internal_deposit(&sender_id, amount.0);
+        if is_native(token_name) {
+            panic!("NEAR is deposited with deposit_near");
+        }
+        let message = parse_message(&msg);
+        if self.is_measured(token_name) {
+            return PromiseOrValue::Promise(
+                self.measured_deposit(token_name, sender_id, amount, msg),
+            );
+        }
+        self.internal_on_transfer(token_name, &sender_id, amount.0, message);
         PromiseOrValue::Value(U128::from(0_u128))
     }
 }
@@ -427,7 +957,51 @@ impl FungibleTokenReceiver for AMM {
 mod tests {
     use super::*;
     use near_contract_standards::fungible_token::metadata::FT_METADATA_SPEC;
-    use near_sdk::test_utils::accounts;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, RuntimeFeesConfig, VMConfig};
+    use proptest::prelude::*;
+    use utils::U256;
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    fn with_promise_results(context: &VMContextBuilder, promise_results: Vec<PromiseResult>) {
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            promise_results
+        );
+    }
+
+    /// Pool of 1_000_000 FTA and FTB owned by accounts(0), with accounts(1)
+    /// holding 100_000 of each on its internal balances.
+    fn init_pool() -> AMM {
+        testing_env!(get_context(accounts(1)).build());
+        let mut amm = AMM::new(accounts(0), accounts(2), accounts(3), None);
+        amm.set_metadata_a(meta_a());
+        amm.set_metadata_b(meta_b());
+        for token in [&mut amm.token_a.0, &mut amm.token_b.0] {
+            token.internal_deposit(&accounts(0), 1_000_000);
+            register_account(token, &accounts(1));
+            token.internal_deposit(&accounts(1), 100_000);
+        }
+        amm
+    }
+
+    /// Enough for the storage of an order or a lock.
+    const STORAGE_DEPOSIT: Balance = 10_000_000_000_000_000_000_000;
+
+    fn balance_result(balance: Balance) -> PromiseResult {
+        PromiseResult::Successful(json!(U128(balance)).to_string().into_bytes())
+    }
 
     fn meta_a() -> FungibleTokenMetadata {
         FungibleTokenMetadata {
@@ -457,7 +1031,7 @@ mod tests {
         let rick_id = accounts(1);
         let token_a = accounts(2);
         let token_b = accounts(3);
-        let mut amm = AMM::new(rick_id.clone(), token_a.clone(), token_b.clone());
+        let mut amm = AMM::new(rick_id.clone(), token_a.clone(), token_b.clone(), None);
         amm.set_metadata_a(meta_a());
         amm.set_metadata_b(meta_b());
 
@@ -477,7 +1051,7 @@ mod tests {
         let rick_id = accounts(1);
         let token_a = accounts(2);
         let token_b = accounts(3);
-        let amm = AMM::new(rick_id.clone(), token_a.clone(), token_b.clone());
+        let amm = AMM::new(rick_id.clone(), token_a.clone(), token_b.clone(), None);
         amm.ft_metadata_a();
     }
 
@@ -487,7 +1061,7 @@ mod tests {
         let rick_id = accounts(1);
         let token_a = accounts(2);
         let token_b = accounts(3);
-        let amm = AMM::new(rick_id.clone(), token_a.clone(), token_b.clone());
+        let amm = AMM::new(rick_id.clone(), token_a.clone(), token_b.clone(), None);
         amm.ft_metadata_b();
     }
 
@@ -497,7 +1071,7 @@ mod tests {
         let rick_id = accounts(1);
         let token_a = accounts(2);
         let token_b = accounts(3);
-        let mut amm = AMM::new(rick_id.clone(), token_a.clone(), token_b.clone());
+        let mut amm = AMM::new(rick_id.clone(), token_a.clone(), token_b.clone(), None);
         amm.set_metadata_a(meta_a());
         amm.set_metadata_a(meta_a());
     }
@@ -508,7 +1082,7 @@ mod tests {
         let rick_id = accounts(1);
         let token_a = accounts(2);
         let token_b = accounts(3);
-        let mut amm = AMM::new(rick_id.clone(), token_a.clone(), token_b.clone());
+        let mut amm = AMM::new(rick_id.clone(), token_a.clone(), token_b.clone(), None);
         amm.set_metadata_b(meta_b());
         amm.set_metadata_b(meta_b());
     }
@@ -519,7 +1093,7 @@ mod tests {
         let rick_id = accounts(1);
         let token_a = accounts(2);
         let token_b = accounts(3);
-        let mut amm = AMM::new(rick_id, token_a, token_b);
+        let mut amm = AMM::new(rick_id, token_a, token_b, None);
         amm.set_metadata_a(meta_a());
         amm.set_metadata_b(meta_a());
     }
@@ -530,7 +1104,7 @@ mod tests {
         let rick_id = accounts(1);
         let token_a = accounts(2);
         let token_b = accounts(3);
-        let mut amm = AMM::new(rick_id, token_a, token_b);
+        let mut amm = AMM::new(rick_id, token_a, token_b, None);
         amm.set_metadata_b(meta_b());
         amm.set_metadata_a(meta_b());
     }
@@ -543,7 +1117,7 @@ mod tests {
         let token_morty = accounts(3);
         let token_zombie = accounts(4);
         let amount = 10_000_u128;
-        let mut amm = AMM::new(owner, token_rick, token_morty);
+        let mut amm = AMM::new(owner, token_rick, token_morty, None);
         amm.set_metadata_a(meta_a());
         amm.set_metadata_b(meta_b());
         amm.add_token_to_pool(token_zombie, amount.into(), None);
@@ -556,7 +1130,7 @@ mod tests {
         let token_rick = accounts(2);
         let token_morty = accounts(3);
         let amount = 10_000_u128;
-        let mut amm = AMM::new(owner, token_rick.clone(), token_morty.clone());
+        let mut amm = AMM::new(owner, token_rick.clone(), token_morty.clone(), None);
         amm.add_token_to_pool(token_rick, amount.into(), None);
     }
 
@@ -567,8 +1141,8 @@ mod tests {
         let token_rick = accounts(2);
         let token_morty = accounts(3);
         let amount = 10_000_u128;
-        let mut amm = AMM::new(owner, token_rick.clone(), token_morty);
-        amm.swap(token_rick.clone(), token_rick, amount.into());
+        let mut amm = AMM::new(owner, token_rick.clone(), token_morty, None);
+        amm.swap(token_rick.clone(), token_rick, amount.into(), None);
     }
 
     #[test]
@@ -579,9 +1153,1257 @@ mod tests {
         let token_morty = accounts(3);
         let token_zombie = accounts(4);
         let amount = 10_000_u128;
-        let mut amm = AMM::new(owner, token_rick.clone(), token_morty);
+        let mut amm = AMM::new(owner, token_rick.clone(), token_morty, None);
         amm.set_metadata_a(meta_a());
         amm.set_metadata_b(meta_b());
-        amm.swap(token_rick, token_zombie, amount.into());
+        amm.swap(token_rick, token_zombie, amount.into(), None);
+    }
+
+    #[test]
+    fn test_sync_moves_drift_into_pool() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut amm = AMM::new(accounts(1), accounts(2), accounts(3), None);
+        amm.token_a.0.internal_deposit(&accounts(0), 1_000);
+        amm.token_b.0.internal_deposit(&accounts(0), 1_000);
+
+        with_promise_results(&context, vec![balance_result(1_200), balance_result(900)]);
+        amm.sync_callback();
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(0)).0, 1_200);
+        assert_eq!(amm.ft_balance_of(accounts(3), accounts(0)).0, 900);
+    }
+
+    #[test]
+    fn test_skim_hands_out_surplus() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut amm = AMM::new(accounts(1), accounts(2), accounts(3), None);
+        amm.token_a.0.internal_deposit(&accounts(0), 1_000);
+
+        with_promise_results(&context, vec![balance_result(1_050), balance_result(0)]);
+        amm.skim_callback(accounts(1));
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(0)).0, 1_000);
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(1)).0, 50);
+
+        // Accounts that never registered get registered
+        amm.token_b.0.internal_deposit(&accounts(0), 1_000);
+        with_promise_results(&context, vec![balance_result(1_050), balance_result(1_020)]);
+        amm.skim_callback(accounts(4));
+        assert_eq!(amm.ft_balance_of(accounts(3), accounts(4)).0, 20);
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(4)).0, 0);
+    }
+
+    #[test]
+    fn test_withdraw_tokens_refunds_failed_transfer() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut amm = AMM::new(accounts(1), accounts(2), accounts(3), None);
+        amm.token_a.0.internal_deposit(&accounts(1), 500);
+        amm.withdraw_tokens(accounts(2), U128(500));
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(1)).0, 0);
+
+        with_promise_results(&context, vec![PromiseResult::Failed]);
+        amm.withdraw_tokens_callback(accounts(2), accounts(1), U128(500));
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(1)).0, 500);
+    }
+
+    #[test]
+    #[should_panic(expected = "Withdrawals are in flight, try again")]
+    fn test_sync_while_withdrawing() {
+        let mut amm = init_pool();
+        amm.withdraw_tokens(accounts(2), U128(1_000));
+        testing_env!(get_context(accounts(0)).build());
+        amm.sync();
+    }
+
+    #[test]
+    #[should_panic(expected = "A measured deposit is in flight, try again")]
+    fn test_skim_while_deposit_is_measured() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(0)).build());
+        amm.set_measured_deposits(accounts(2), true);
+        testing_env!(get_context(accounts(2)).build());
+        amm.ft_on_transfer(accounts(1), U128(100_000), "".to_string());
+
+        // The deposit has arrived but isn't credited, it is no surplus
+        testing_env!(get_context(accounts(0)).build());
+        amm.skim(accounts(4));
+    }
+
+    #[test]
+    #[should_panic(expected = "The reserves are being reconciled, try again")]
+    fn test_withdraw_while_syncing() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(0)).build());
+        amm.sync();
+        testing_env!(get_context(accounts(1)).build());
+        amm.withdraw_tokens(accounts(3), U128(1_000));
+    }
+
+    #[test]
+    fn test_sync_callback_releases_transfers() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(0)).build());
+        amm.set_measured_deposits(accounts(2), true);
+        amm.sync();
+
+        with_promise_results(
+            &get_context(accounts(0)),
+            vec![balance_result(1_100_000), balance_result(1_100_000)],
+        );
+        amm.sync_callback();
+        testing_env!(get_context(accounts(1)).build());
+        amm.withdraw_tokens(accounts(3), U128(1_000));
+        testing_env!(get_context(accounts(2)).build());
+        amm.ft_on_transfer(accounts(1), U128(100_000), "".to_string());
+        assert!(amm.pending_deposit.is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_sync_not_owner() {
+        testing_env!(get_context(accounts(4)).build());
+        let mut amm = AMM::new(accounts(1), accounts(2), accounts(3), None);
+        amm.sync();
+    }
+
+    #[test]
+    fn test_swap_takes_fee_and_protocol_cut() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(0)).build());
+        amm.set_treasury(accounts(4));
+        amm.set_protocol_fee(1_667);
+
+        testing_env!(get_context(accounts(1)).build());
+        let bought = amm.swap(accounts(3), accounts(2), U128(100_000), None);
+        assert_eq!(bought.0, 90_661);
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(0)).0, 1_099_950);
+        assert_eq!(amm.ft_balance_of(accounts(3), accounts(0)).0, 1_000_000 - 90_661);
+
+        testing_env!(get_context(accounts(4)).build());
+        assert_eq!(amm.claim_protocol_fees(), (U128(50), U128(0)));
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(4)).0, 50);
+    }
+
+    #[test]
+    #[should_panic(expected = "The protocol fee can't be more than 5000")]
+    fn test_protocol_fee_hard_maximum() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(0)).build());
+        amm.set_treasury(accounts(4));
+        amm.set_protocol_fee(5_001);
+    }
+
+    #[test]
+    fn test_swap_pays_whitelisted_referrer() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(0)).build());
+        amm.add_referrer(accounts(4));
+        amm.set_referral_fee(2_000);
+
+        testing_env!(get_context(accounts(1)).build());
+        amm.swap(accounts(3), accounts(2), U128(100_000), Some(accounts(4)));
+        amm.swap(accounts(2), accounts(3), U128(100_000), Some(accounts(5)));
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(4)).0, 60);
+        assert_eq!(amm.get_referral_fees(accounts(4)), (U128(60), U128(0)));
+        // accounts(5) is not whitelisted, the whole sold amount goes to the pool
+        assert_eq!(amm.ft_balance_of(accounts(3), accounts(0)).0, 909_339 + 100_000);
+    }
+
+    proptest! {
+        #[test]
+        fn swaps_with_referral_keep_k_and_tokens(
+            x in 1_000..1_000_000_000_000_000_000_000_000_000_000_u128,
+            y in 1_000..1_000_000_000_000_000_000_000_000_000_000_u128,
+            swap_fee in 0..1_000_u32,
+            protocol_fee in 0..5_000_u32,
+            referral_fee in 0..5_000_u32,
+            swaps in prop::collection::vec(
+                (any::<bool>(), 1..1_000_000_000_000_000_000_000_000_000_u128),
+                1..16,
+            ),
+        ) {
+            // Every case starts from empty storage, `testing_env!` keeps it
+            env::set_blockchain_interface(near_sdk::MockedBlockchain::new(
+                get_context(accounts(0)).build(),
+                VMConfig::test(),
+                RuntimeFeesConfig::test(),
+                vec![],
+                Default::default(),
+                Default::default(),
+                None,
+            ));
+            let mut amm = AMM::new(accounts(0), accounts(2), accounts(3), Some(swap_fee));
+            amm.set_metadata_a(meta_a());
+            amm.set_metadata_b(meta_b());
+            amm.set_treasury(accounts(5));
+            amm.set_protocol_fee(protocol_fee);
+            amm.add_referrer(accounts(4));
+            amm.set_referral_fee(referral_fee);
+            let user_balance = 1_000_000_000_000_000_000_000_000_000_000_000_u128;
+            for (token, reserve) in [(&mut amm.token_a.0, x), (&mut amm.token_b.0, y)] {
+                token.internal_deposit(&accounts(0), reserve);
+                register_account(token, &accounts(1));
+                token.internal_deposit(&accounts(1), user_balance);
+            }
+
+            testing_env!(get_context(accounts(1)).build());
+            for (sell_a, amount) in swaps {
+                let (sell_token, buy_token) = if sell_a {
+                    (accounts(2), accounts(3))
+                } else {
+                    (accounts(3), accounts(2))
+                };
+                if amm.get_return(buy_token.clone(), sell_token.clone(), U128(amount)).0 == 0 {
+                    continue;
+                }
+                let k = U256::from(amm.token_a.0.internal_unwrap_balance_of(&accounts(0)))
+                    * U256::from(amm.token_b.0.internal_unwrap_balance_of(&accounts(0)));
+                amm.swap(buy_token, sell_token, U128(amount), Some(accounts(4)));
+                let new_k = U256::from(amm.token_a.0.internal_unwrap_balance_of(&accounts(0)))
+                    * U256::from(amm.token_b.0.internal_unwrap_balance_of(&accounts(0)));
+                prop_assert!(new_k >= k);
+            }
+            // The cuts of the referrer and the protocol come out of the sold
+            // tokens, nothing is created or lost
+            let protocol_fees_id = protocol_fees_account();
+            for (token, reserve) in [(&amm.token_a.0, x), (&amm.token_b.0, y)] {
+                let held = [&accounts(0), &accounts(1), &accounts(4), &protocol_fees_id]
+                    .iter()
+                    .map(|account_id| token.accounts.get(account_id).unwrap_or(0))
+                    .sum::<Balance>();
+                prop_assert_eq!(held, reserve + user_balance);
+            }
+        }
+    }
+
+    #[test]
+    fn test_ft_on_transfer_swap_msg() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(2)).build());
+        amm.ft_on_transfer(
+            accounts(1),
+            U128(100_000),
+            json!({ "swap": { "buy_token_name": accounts(3) } }).to_string(),
+        );
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(1)).0, 100_000);
+        assert_eq!(amm.ft_balance_of(accounts(3), accounts(1)).0, 100_000 + 90_661);
+    }
+
+    #[test]
+    fn test_flash_loan_repaid() {
+        let mut amm = init_pool();
+        amm.flash_loan(accounts(2), U128(10_000), accounts(5), "".to_string());
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(0)).0, 990_000);
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(1)).0, 100_000 - 10_009);
+
+        testing_env!(get_context(accounts(2)).build());
+        amm.ft_on_transfer(
+            accounts(5),
+            U128(10_009),
+            json!({ "repay_flash_loan": {} }).to_string(),
+        );
+        testing_env!(get_context(accounts(0)).build());
+        assert!(matches!(amm.resolve_flash_loan(), PromiseOrValue::Value(true)));
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(0)).0, 1_000_009);
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(1)).0, 100_000);
+    }
+
+    #[test]
+    fn test_flash_loan_shortfall_taken_from_borrower() {
+        let mut amm = init_pool();
+        amm.flash_loan(accounts(2), U128(10_000), accounts(5), "".to_string());
+
+        testing_env!(get_context(accounts(2)).build());
+        amm.ft_on_transfer(
+            accounts(5),
+            U128(5_000),
+            json!({ "repay_flash_loan": {} }).to_string(),
+        );
+        testing_env!(get_context(accounts(0)).build());
+        assert!(matches!(amm.resolve_flash_loan(), PromiseOrValue::Promise(_)));
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(0)).0, 1_000_009);
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(1)).0, 100_000 - 5_009);
+    }
+
+    #[test]
+    fn test_flash_loan_cleared_by_owner() {
+        let mut amm = init_pool();
+        amm.flash_loan(accounts(2), U128(10_000), accounts(5), "".to_string());
+
+        // The callbacks never ran, the escrow pays the pool back
+        testing_env!(get_context(accounts(0))
+            .block_index(flash_loan::FLASH_LOAN_TIMEOUT)
+            .build());
+        amm.clear_flash_loan();
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(0)).0, 1_000_009);
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(1)).0, 100_000 - 10_009);
+        testing_env!(get_context(accounts(1)).build());
+        amm.swap(accounts(3), accounts(2), U128(1_000), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "The flash loan can't be cleared before block")]
+    fn test_flash_loan_cleared_too_early() {
+        let mut amm = init_pool();
+        amm.flash_loan(accounts(2), U128(10_000), accounts(5), "".to_string());
+        testing_env!(get_context(accounts(0)).block_index(10).build());
+        amm.clear_flash_loan();
+    }
+
+    #[test]
+    #[should_panic(expected = "The pool is locked by a flash loan")]
+    fn test_swap_during_flash_loan() {
+        let mut amm = init_pool();
+        amm.flash_loan(accounts(2), U128(10_000), accounts(5), "".to_string());
+        amm.swap(accounts(3), accounts(2), U128(1_000), None);
+    }
+
+    #[test]
+    fn test_limit_order_fills_once_price_is_reached() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(1)).attached_deposit(STORAGE_DEPOSIT).build());
+        let order_id = amm.place_order(accounts(2), accounts(3), U128(10_000), U128(10_000));
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(1)).0, 90_000);
+
+        testing_env!(get_context(accounts(4)).build());
+        register_account(&mut amm.token_a.0, &accounts(4));
+        register_account(&mut amm.token_b.0, &accounts(4));
+        assert!(amm.execute_orders(vec![order_id]).is_empty());
+
+        // Buying token A moves its price up to the limit
+        testing_env!(get_context(accounts(1)).build());
+        amm.swap(accounts(2), accounts(3), U128(50_000), None);
+        let quote = amm.get_return(accounts(3), accounts(2), U128(10_000)).0;
+        let balance_b = amm.ft_balance_of(accounts(3), accounts(1)).0;
+
+        testing_env!(get_context(accounts(4)).build());
+        assert_eq!(amm.execute_orders(vec![order_id]), vec![order_id]);
+        let bounty = quote / 1_000;
+        assert_eq!(amm.ft_balance_of(accounts(3), accounts(4)).0, bounty);
+        assert_eq!(amm.ft_balance_of(accounts(3), accounts(1)).0, balance_b + quote - bounty);
+        assert!(amm.get_order(order_id).is_none());
+    }
+
+    #[test]
+    fn test_cancel_order_returns_escrow() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(1)).attached_deposit(STORAGE_DEPOSIT).build());
+        let order_id = amm.place_order(accounts(2), accounts(3), U128(10_000), U128(20_000));
+        assert_eq!(
+            amm.get_account_orders(accounts(1)),
+            json!([{
+                "order_id": 0,
+                "owner_id": accounts(1),
+                "sell_token_name": accounts(2),
+                "buy_token_name": accounts(3),
+                "sell_amount": "10000",
+                "min_buy_amount": "20000",
+            }])
+            .to_string()
+        );
+        testing_env!(get_context(accounts(1)).build());
+        amm.cancel_order(order_id);
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(1)).0, 100_000);
+        assert_eq!(amm.get_orders(0, 10), "[]");
+        assert_eq!(amm.get_account_orders(accounts(1)), "[]");
+    }
+
+    #[test]
+    fn test_order_storage_is_refunded() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(1)).attached_deposit(STORAGE_DEPOSIT).build());
+        let storage = env::storage_usage();
+        let order_id = amm.place_order(accounts(2), accounts(3), U128(10_000), U128(20_000));
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        let cost = (env::storage_usage() - storage) as Balance * env::storage_byte_cost();
+        assert!(matches!(
+            &receipts[0].actions[0],
+            near_sdk::mock::VmAction::Transfer { deposit } if *deposit == STORAGE_DEPOSIT - cost
+        ));
+
+        // The escrow account stays registered, the order itself is refunded
+        testing_env!(get_context(accounts(1)).build());
+        let storage = env::storage_usage();
+        amm.cancel_order(order_id);
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        let refund = (storage - env::storage_usage()) as Balance * env::storage_byte_cost();
+        assert!(refund > 0 && refund < cost);
+        assert!(matches!(
+            &receipts[0].actions[0],
+            near_sdk::mock::VmAction::Transfer { deposit } if *deposit == refund
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "Please attach at least")]
+    fn test_place_order_without_storage_deposit() {
+        let mut amm = init_pool();
+        amm.place_order(accounts(2), accounts(3), U128(10_000), U128(20_000));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner of the order can cancel it")]
+    fn test_cancel_order_not_owner() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(1)).attached_deposit(STORAGE_DEPOSIT).build());
+        let order_id = amm.place_order(accounts(2), accounts(3), U128(10_000), U128(20_000));
+        testing_env!(get_context(accounts(4)).build());
+        amm.cancel_order(order_id);
+    }
+
+    #[test]
+    fn test_schedule_swaps_once_per_interval() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(1)).attached_deposit(STORAGE_DEPOSIT).build());
+        let schedule_id = amm.create_schedule(accounts(2), accounts(3), U128(10_000), U128(0), 60, 2);
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(1)).0, 80_000);
+
+        let schedules: serde_json::Value =
+            serde_json::from_str(&amm.get_account_schedules(accounts(1))).unwrap();
+        assert_eq!(schedules[0]["schedule_id"], schedule_id);
+        assert_eq!(amm.get_account_schedules(accounts(4)), "[]");
+
+        testing_env!(get_context(accounts(4)).build());
+        assert_eq!(amm.execute_due_schedules(0, 10), vec![schedule_id]);
+        assert!(amm.execute_due_schedules(0, 10).is_empty());
+        let bought = amm.ft_balance_of(accounts(3), accounts(1)).0 - 100_000;
+        let progress: serde_json::Value =
+            serde_json::from_str(&amm.get_schedule(schedule_id).unwrap()).unwrap();
+        assert_eq!(progress["executed_swaps"], 1);
+        assert_eq!(progress["bought"], bought.to_string());
+        assert_eq!(progress["remaining_escrow"], "10000");
+
+        testing_env!(get_context(accounts(4)).block_timestamp(60_000_000_000).build());
+        assert_eq!(amm.execute_due_schedules(0, 10), vec![schedule_id]);
+        assert!(amm.get_schedule(schedule_id).is_none());
+        assert_eq!(amm.get_account_schedules(accounts(1)), "[]");
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(0)).0, 1_020_000);
+    }
+
+    #[test]
+    fn test_late_schedule_does_not_catch_up() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(1)).attached_deposit(STORAGE_DEPOSIT).build());
+        let schedule_id = amm.create_schedule(accounts(2), accounts(3), U128(10_000), U128(0), 60, 3);
+
+        testing_env!(get_context(accounts(4)).block_timestamp(600_000_000_000).build());
+        assert_eq!(amm.execute_due_schedules(0, 10), vec![schedule_id]);
+        assert!(amm.execute_due_schedules(0, 10).is_empty());
+        let progress: serde_json::Value =
+            serde_json::from_str(&amm.get_schedule(schedule_id).unwrap()).unwrap();
+        assert_eq!(progress["next_swap_at"], 660_000_000_000_u64);
+    }
+
+    #[test]
+    #[should_panic(expected = "The interval must be between 1 and")]
+    fn test_schedule_without_interval() {
+        let mut amm = init_pool();
+        amm.create_schedule(accounts(2), accounts(3), U128(10_000), U128(0), 0, 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "The interval must be between 1 and")]
+    fn test_schedule_with_overflowing_interval() {
+        let mut amm = init_pool();
+        amm.create_schedule(accounts(2), accounts(3), U128(10_000), U128(0), u64::MAX, 3);
+    }
+
+    #[test]
+    fn test_schedule_waits_for_slippage_limit() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(1)).attached_deposit(STORAGE_DEPOSIT).build());
+        let schedule_id =
+            amm.create_schedule(accounts(2), accounts(3), U128(10_000), U128(10_000), 60, 2);
+        testing_env!(get_context(accounts(1)).build());
+        assert!(amm.execute_due_schedules(0, 10).is_empty());
+
+        amm.cancel_schedule(schedule_id);
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(1)).0, 100_000);
+        assert_eq!(amm.get_account_schedules(accounts(1)), "[]");
+    }
+
+    #[test]
+    #[should_panic(expected = "Please attach at least")]
+    fn test_create_schedule_without_storage_deposit() {
+        let mut amm = init_pool();
+        amm.create_schedule(accounts(2), accounts(3), U128(10_000), U128(0), 60, 2);
+    }
+
+    #[test]
+    fn test_schedule_storage_is_refunded_when_done() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(1)).attached_deposit(STORAGE_DEPOSIT).build());
+        let storage = env::storage_usage();
+        amm.create_schedule(accounts(2), accounts(3), U128(10_000), U128(0), 60, 1);
+        let cost = (env::storage_usage() - storage) as Balance * env::storage_byte_cost();
+
+        testing_env!(get_context(accounts(4)).build());
+        let storage = env::storage_usage();
+        amm.execute_due_schedules(0, 10);
+        let refund = (storage - env::storage_usage()) as Balance * env::storage_byte_cost();
+        assert!(refund > 0 && refund < cost);
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        assert_eq!(receipts[0].receiver_id, accounts(1));
+        assert!(matches!(
+            &receipts[0].actions[0],
+            near_sdk::mock::VmAction::Transfer { deposit } if *deposit == refund
+        ));
+    }
+
+    #[test]
+    fn test_farm_rewards_split_by_stake() {
+        let mut amm = init_pool();
+        for (account_id, shares) in [(accounts(1), 1_000), (accounts(4), 3_000)] {
+            register_account(&mut amm.token_amm, &account_id);
+            amm.token_amm.internal_deposit(&account_id, shares);
+        }
+        testing_env!(get_context(accounts(0)).build());
+        let farm_id = amm.add_farm(accounts(2), 0, 100_000_000_000);
+
+        testing_env!(get_context(accounts(1)).attached_deposit(STORAGE_DEPOSIT).build());
+        amm.stake_shares(U128(1_000));
+        testing_env!(get_context(accounts(4)).attached_deposit(STORAGE_DEPOSIT).build());
+        amm.stake_shares(U128(3_000));
+        testing_env!(get_context(accounts(2)).build());
+        amm.ft_on_transfer(
+            accounts(5),
+            U128(10_000),
+            json!({ "fund_farm": { "farm_id": farm_id } }).to_string(),
+        );
+
+        testing_env!(get_context(accounts(1)).block_timestamp(50_000_000_000).build());
+        assert!(matches!(amm.claim_rewards(farm_id), PromiseOrValue::Value(U128(1_250))));
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(1)).0, 101_250);
+        assert_eq!(amm.get_unclaimed_rewards(farm_id, accounts(4)).0, 3_750);
+
+        testing_env!(get_context(accounts(1)).block_timestamp(200_000_000_000).build());
+        amm.unstake_shares(U128(1_000));
+        assert_eq!(amm.ft_balance_of(env::current_account_id(), accounts(1)).0, 1_000);
+        assert_eq!(amm.get_unclaimed_rewards(farm_id, accounts(1)).0, 1_250);
+        assert_eq!(amm.get_unclaimed_rewards(farm_id, accounts(4)).0, 7_500);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough staked shares")]
+    fn test_unstake_more_than_staked() {
+        let mut amm = init_pool();
+        register_account(&mut amm.token_amm, &accounts(1));
+        amm.token_amm.internal_deposit(&accounts(1), 1_000);
+        testing_env!(get_context(accounts(1)).attached_deposit(STORAGE_DEPOSIT).build());
+        amm.stake_shares(U128(1_000));
+        amm.unstake_shares(U128(1_001));
+    }
+
+    #[test]
+    #[should_panic(expected = "Please attach at least")]
+    fn test_stake_shares_without_storage_deposit() {
+        let mut amm = init_pool();
+        register_account(&mut amm.token_amm, &accounts(1));
+        amm.token_amm.internal_deposit(&accounts(1), 1_000);
+        amm.stake_shares(U128(1_000));
+    }
+
+    #[test]
+    fn test_stake_storage_is_refunded_on_exit() {
+        let mut amm = init_pool();
+        register_account(&mut amm.token_amm, &accounts(1));
+        amm.token_amm.internal_deposit(&accounts(1), 1_000);
+        testing_env!(get_context(accounts(0)).build());
+        amm.add_farm(accounts(2), 0, 100_000_000_000);
+        testing_env!(get_context(accounts(1)).attached_deposit(STORAGE_DEPOSIT).build());
+        let storage = env::storage_usage();
+        amm.stake_shares(U128(1_000));
+        let cost = (env::storage_usage() - storage) as Balance * env::storage_byte_cost();
+
+        // Nothing was funded, so nothing is left to claim after unstaking
+        testing_env!(get_context(accounts(1)).build());
+        let storage = env::storage_usage();
+        amm.unstake_shares(U128(1_000));
+        let refund = (storage - env::storage_usage()) as Balance * env::storage_byte_cost();
+        assert!(refund > 0 && refund < cost);
+        assert!(amm.farmers.get(&accounts(1)).is_none());
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        assert!(matches!(
+            &receipts[0].actions[0],
+            near_sdk::mock::VmAction::Transfer { deposit } if *deposit == refund
+        ));
+    }
+
+    #[test]
+    fn test_withdraw_leftover_of_farm_without_stakers() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(0)).build());
+        let farm_id = amm.add_farm(accounts(2), 0, 100_000_000_000);
+        testing_env!(get_context(accounts(2)).build());
+        amm.ft_on_transfer(
+            accounts(5),
+            U128(10_000),
+            json!({ "fund_farm": { "farm_id": farm_id } }).to_string(),
+        );
+
+        testing_env!(get_context(accounts(0)).block_timestamp(100_000_000_000).build());
+        let balance = amm.ft_balance_of(accounts(2), accounts(0)).0;
+        assert!(matches!(
+            amm.withdraw_farm_leftover(farm_id),
+            PromiseOrValue::Value(U128(10_000))
+        ));
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(0)).0, balance + 10_000);
+        assert!(matches!(amm.withdraw_farm_leftover(farm_id), PromiseOrValue::Value(U128(0))));
+    }
+
+    #[test]
+    #[should_panic(expected = "The farm has not ended")]
+    fn test_withdraw_leftover_of_running_farm() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(0)).build());
+        let farm_id = amm.add_farm(accounts(2), 0, 100_000_000_000);
+        amm.withdraw_farm_leftover(farm_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_withdraw_farm_leftover_not_owner() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(0)).build());
+        let farm_id = amm.add_farm(accounts(2), 0, 100_000_000_000);
+        testing_env!(get_context(accounts(1)).block_timestamp(100_000_000_000).build());
+        amm.withdraw_farm_leftover(farm_id);
+    }
+
+    #[test]
+    fn test_locked_liquidity_returns_after_unlock_time() {
+        let mut amm = init_pool();
+        register_account(&mut amm.token_amm, &accounts(1));
+        amm.token_amm.internal_deposit(&accounts(1), 1_000);
+        testing_env!(get_context(accounts(1)).attached_deposit(STORAGE_DEPOSIT).build());
+        let lock_id = amm.lock_liquidity(U128(400), 60_000_000_000);
+        assert_eq!(amm.ft_balance_of(env::current_account_id(), accounts(1)).0, 600);
+        assert_eq!(
+            amm.get_account_locks(accounts(1)),
+            json!([{
+                "lock_id": lock_id,
+                "owner_id": accounts(1),
+                "shares": "400",
+                "unlock_at": 60_000_000_000_u64,
+            }])
+            .to_string()
+        );
+        assert_eq!(
+            amm.get_locked_liquidity(),
+            json!({ "locked_shares": "400", "total_shares": "1000", "locked_bps": 4_000 })
+                .to_string()
+        );
+
+        testing_env!(get_context(accounts(1)).block_timestamp(60_000_000_000).build());
+        assert_eq!(amm.unlock_liquidity(lock_id).0, 400);
+        assert_eq!(amm.ft_balance_of(env::current_account_id(), accounts(1)).0, 1_000);
+        assert_eq!(amm.get_account_locks(accounts(1)), "[]");
+    }
+
+    #[test]
+    #[should_panic(expected = "The liquidity is still locked")]
+    fn test_unlock_liquidity_too_early() {
+        let mut amm = init_pool();
+        register_account(&mut amm.token_amm, &accounts(1));
+        amm.token_amm.internal_deposit(&accounts(1), 1_000);
+        testing_env!(get_context(accounts(1)).attached_deposit(STORAGE_DEPOSIT).build());
+        let lock_id = amm.lock_liquidity(U128(400), 60_000_000_000);
+        amm.unlock_liquidity(lock_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "Please attach at least")]
+    fn test_lock_liquidity_without_storage_deposit() {
+        let mut amm = init_pool();
+        register_account(&mut amm.token_amm, &accounts(1));
+        amm.token_amm.internal_deposit(&accounts(1), 1_000);
+        amm.lock_liquidity(U128(400), 60_000_000_000);
+    }
+
+    #[test]
+    fn test_first_mint_locks_minimum_liquidity() {
+        let mut amm = init_pool();
+        register_account(&mut amm.token_amm, &accounts(1));
+        amm.add_token_to_pool(accounts(2), U128(10_000), None);
+        assert_eq!(amm.ft_balance_of(env::current_account_id(), accounts(1)).0, 9_000);
+        assert_eq!(amm.token_amm.total_supply, 10_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "The deposit is too small to mint any shares")]
+    fn test_add_token_to_pool_zero_shares() {
+        let mut amm = init_pool();
+        register_account(&mut amm.token_amm, &accounts(1));
+        amm.add_token_to_pool(accounts(2), U128(10_000), None);
+        amm.add_token_to_pool(accounts(2), U128(100), None);
+    }
+
+    #[test]
+    fn test_migrate_first_release_state() {
+        testing_env!(get_context(accounts(0)).build());
+        let mut token_a = FungibleToken::new(b"a".to_vec());
+        token_a.internal_register_account(&accounts(1));
+        token_a.internal_deposit(&accounts(1), 1_000);
+        env::state_write(&migration::AMMV1 {
+            token_amm: FungibleToken::new(b"amm".to_vec()),
+            token_a: (token_a, Some(meta_a())),
+            token_b: (FungibleToken::new(b"b".to_vec()), None),
+            account_id_token_a: accounts(2),
+            account_id_token_b: accounts(3),
+        });
+
+        let amm = AMM::migrate();
+        assert_eq!(amm.get_state_version(), "V2");
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(1)).0, 1_000);
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(0)).0, 0);
+        assert_eq!(amm.owner_id, accounts(0));
+        assert_eq!(amm.upgrade_delay, DEFAULT_UPGRADE_DELAY);
+    }
+
+    #[test]
+    fn test_migrate_current_state() {
+        let mut amm = init_pool();
+        register_account(&mut amm.token_amm, &accounts(1));
+        amm.add_token_to_pool(accounts(2), U128(100_000), None);
+        amm.swap(accounts(2), accounts(3), U128(10_000), None);
+        amm.swap_fee = 10;
+        amm.upgrade_delay = DEFAULT_UPGRADE_DELAY + 1;
+        let stats = amm.get_stats();
+        let position = amm.get_position(accounts(1));
+        env::state_write(&amm);
+
+        testing_env!(get_context(accounts(0)).build());
+        let amm = AMM::migrate();
+        assert_eq!(amm.get_state_version(), "V2");
+        assert_eq!(amm.swap_fee, 10);
+        assert_eq!(amm.upgrade_delay, DEFAULT_UPGRADE_DELAY + 1);
+        assert_eq!(amm.get_stats(), stats);
+        assert_eq!(amm.get_position(accounts(1)), position);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown state version")]
+    fn test_migrate_unknown_state_version() {
+        testing_env!(get_context(accounts(0)).build());
+        env::storage_write(b"STATE", &[7, 0, 0, 0]);
+        AMM::migrate();
+    }
+
+    #[test]
+    fn test_upgrade_after_delay() {
+        let mut amm = init_pool();
+        let mut context = get_context(accounts(0)).build();
+        context.input = b"new code".to_vec();
+        testing_env!(context);
+        amm.stage_upgrade();
+        assert_eq!(
+            env::storage_read(b"upgrade-code"),
+            Some(b"new code".to_vec())
+        );
+
+        testing_env!(get_context(accounts(0))
+            .block_timestamp(DEFAULT_UPGRADE_DELAY * 1_000_000_000)
+            .prepaid_gas(Gas(300_000_000_000_000))
+            .build());
+        amm.upgrade();
+        assert!(amm.staged_upgrade.is_none());
+        assert!(env::storage_read(b"upgrade-code").is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "The upgrade can't be deployed before 172800000000000")]
+    fn test_upgrade_before_delay() {
+        let mut amm = init_pool();
+        let mut context = get_context(accounts(0)).build();
+        context.input = b"new code".to_vec();
+        testing_env!(context);
+        amm.stage_upgrade();
+        amm.upgrade();
+    }
+
+    #[test]
+    #[should_panic(expected = "The upgrade delay can't be shortened")]
+    fn test_upgrade_delay_only_grows() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(0)).build());
+        amm.set_upgrade_delay(DEFAULT_UPGRADE_DELAY + 60);
+        amm.set_upgrade_delay(DEFAULT_UPGRADE_DELAY);
+    }
+
+    #[test]
+    #[should_panic(expected = "The upgrade delay can't be more than 2592000")]
+    fn test_upgrade_delay_hard_maximum() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(0)).build());
+        amm.set_upgrade_delay(upgrade::MAX_UPGRADE_DELAY);
+        let mut context = get_context(accounts(0)).build();
+        context.input = b"new code".to_vec();
+        testing_env!(context);
+        amm.stage_upgrade();
+        amm.set_upgrade_delay(upgrade::MAX_UPGRADE_DELAY + 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "The swap fee can't be more than 1000")]
+    fn test_init_swap_fee_hard_maximum() {
+        testing_env!(get_context(accounts(1)).build());
+        AMM::new(accounts(0), accounts(2), accounts(3), Some(1_001));
+    }
+
+    #[test]
+    fn test_measured_deposit_credits_received_amount() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(0)).build());
+        amm.set_measured_deposits(accounts(2), true);
+
+        testing_env!(get_context(accounts(2)).build());
+        let result = amm.ft_on_transfer(accounts(1), U128(100_000), "".to_string());
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(1)).0, 100_000);
+
+        // A 1% tax was taken on the way
+        with_promise_results(&get_context(accounts(0)), vec![balance_result(1_199_000)]);
+        amm.measured_deposit_callback(accounts(2), accounts(1), U128(100_000), "".to_string());
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(1)).0, 199_000);
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(0)).0, 1_000_000);
+    }
+
+    #[test]
+    fn test_measured_deposit_moves_rebase_into_pool() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(0)).build());
+        amm.set_measured_deposits(accounts(2), true);
+
+        with_promise_results(&get_context(accounts(0)), vec![balance_result(1_205_000)]);
+        amm.measured_deposit_callback(
+            accounts(2),
+            accounts(1),
+            U128(100_000),
+            json!({ "swap": { "buy_token_name": accounts(3) } }).to_string(),
+        );
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(1)).0, 100_000);
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(0)).0, 1_105_000);
+        assert!(amm.ft_balance_of(accounts(3), accounts(1)).0 > 100_000);
+    }
+
+    #[test]
+    fn test_measured_deposits_are_serialised() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(0)).build());
+        amm.set_measured_deposits(accounts(2), true);
+
+        testing_env!(get_context(accounts(2)).build());
+        amm.ft_on_transfer(accounts(1), U128(100_000), "".to_string());
+        assert!(amm.pending_deposit.is_some());
+
+        // A failed balance read refunds the deposit and releases the lock
+        with_promise_results(&get_context(accounts(0)), vec![PromiseResult::Failed]);
+        let refund =
+            amm.measured_deposit_callback(accounts(2), accounts(1), U128(100_000), "".to_string());
+        assert_eq!(refund.0, 100_000);
+        assert!(amm.pending_deposit.is_none());
+
+        testing_env!(get_context(accounts(2)).build());
+        amm.ft_on_transfer(accounts(1), U128(100_000), "".to_string());
+        // A lock left behind by a failed msg expires
+        testing_env!(get_context(accounts(2))
+            .block_index(measured::MEASURED_DEPOSIT_TIMEOUT)
+            .build());
+        amm.ft_on_transfer(accounts(1), U128(100_000), "".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Another measured deposit is in flight, try again")]
+    fn test_measured_deposit_while_another_is_in_flight() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(0)).build());
+        amm.set_measured_deposits(accounts(2), true);
+        amm.set_measured_deposits(accounts(3), true);
+
+        testing_env!(get_context(accounts(2)).build());
+        amm.ft_on_transfer(accounts(1), U128(100_000), "".to_string());
+        testing_env!(get_context(accounts(3)).build());
+        amm.ft_on_transfer(accounts(1), U128(100_000), "".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "A deposit of charlie is being measured, try again")]
+    fn test_withdraw_while_deposit_is_measured() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(0)).build());
+        amm.set_measured_deposits(accounts(2), true);
+
+        testing_env!(get_context(accounts(2)).build());
+        amm.ft_on_transfer(accounts(3), U128(100_000), "".to_string());
+        testing_env!(get_context(accounts(1)).build());
+        amm.withdraw_tokens(accounts(2), U128(1_000));
+    }
+
+    #[test]
+    #[should_panic(expected = "Withdrawals of charlie are in flight, try again")]
+    fn test_measured_deposit_while_withdrawing() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(0)).build());
+        amm.set_measured_deposits(accounts(2), true);
+        testing_env!(get_context(accounts(1)).build());
+        amm.withdraw_tokens(accounts(2), U128(1_000));
+
+        testing_env!(get_context(accounts(2)).build());
+        amm.ft_on_transfer(accounts(1), U128(100_000), "".to_string());
+    }
+
+    #[test]
+    fn test_measured_deposit_after_withdrawal() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(0)).build());
+        amm.set_measured_deposits(accounts(2), true);
+        testing_env!(get_context(accounts(1)).build());
+        amm.withdraw_tokens(accounts(2), U128(1_000));
+        assert_eq!(amm.withdrawals_in_flight_a, 1);
+
+        with_promise_results(
+            &get_context(accounts(0)),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        amm.withdraw_tokens_callback(accounts(2), accounts(1), U128(1_000));
+        assert_eq!(amm.withdrawals_in_flight_a, 0);
+        testing_env!(get_context(accounts(2)).build());
+        amm.ft_on_transfer(accounts(1), U128(100_000), "".to_string());
+        assert!(amm.pending_deposit.is_some());
+    }
+
+    #[test]
+    fn test_execute_actions_in_order() {
+        let mut amm = init_pool();
+        register_account(&mut amm.token_amm, &accounts(1));
+        let bought = amm.get_return(accounts(3), accounts(2), U128(10_000));
+        let results = amm.execute_actions(vec![
+            Action::Swap {
+                buy_token_name: accounts(3),
+                sell_token_name: accounts(2),
+                sell_amount: U128(10_000),
+                referral_id: None,
+            },
+            Action::AddTokenToPool {
+                token_name: accounts(3),
+                token_amount: U128(50_000),
+            },
+        ]);
+        assert_eq!(results[0], bought);
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(1)).0, 90_000);
+        assert_eq!(
+            amm.ft_balance_of(accounts(3), accounts(1)).0,
+            100_000 + bought.0 - 50_000
+        );
+        assert_eq!(
+            amm.ft_balance_of(env::current_account_id(), accounts(1)),
+            results[1]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Tokens can't be equals")]
+    fn test_execute_actions_fails_as_a_whole() {
+        let mut amm = init_pool();
+        amm.execute_actions(vec![
+            Action::Swap {
+                buy_token_name: accounts(3),
+                sell_token_name: accounts(2),
+                sell_amount: U128(10_000),
+                referral_id: None,
+            },
+            Action::Swap {
+                buy_token_name: accounts(3),
+                sell_token_name: accounts(3),
+                sell_amount: U128(10_000),
+                referral_id: None,
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_ft_on_transfer_execute_msg() {
+        let mut amm = init_pool();
+        let bought = amm.get_return(accounts(3), accounts(2), U128(10_000));
+        testing_env!(get_context(accounts(2)).build());
+        amm.ft_on_transfer(
+            accounts(1),
+            U128(10_000),
+            json!({ "execute": { "actions": [
+                { "swap": {
+                    "buy_token_name": accounts(3),
+                    "sell_token_name": accounts(2),
+                    "sell_amount": "10000",
+                } },
+                { "withdraw_tokens": { "token_name": accounts(3), "amount": "5000" } },
+            ] } })
+            .to_string(),
+        );
+        assert_eq!(amm.ft_balance_of(accounts(2), accounts(1)).0, 100_000);
+        assert_eq!(
+            amm.ft_balance_of(accounts(3), accounts(1)).0,
+            100_000 + bought.0 - 5_000
+        );
+    }
+
+    #[test]
+    fn test_refresh_metadata() {
+        let mut amm = init_pool();
+        let mut meta = meta_a();
+        meta.name = "Renamed token".to_string();
+        let result = PromiseResult::Successful(json!(meta).to_string().into_bytes());
+        with_promise_results(&get_context(accounts(0)), vec![result]);
+        amm.refresh_metadata_callback(accounts(2));
+        assert_eq!(
+            amm.ft_metadata_a(),
+            json!({ "name": "Renamed token", "decimals": 8 }).to_string()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The decimals of a token can't change")]
+    fn test_refresh_metadata_changed_decimals() {
+        let mut amm = init_pool();
+        let mut meta = meta_a();
+        meta.decimals = 6;
+        let result = PromiseResult::Successful(json!(meta).to_string().into_bytes());
+        with_promise_results(&get_context(accounts(0)), vec![result]);
+        amm.refresh_metadata_callback(accounts(2));
+    }
+
+    #[test]
+    fn test_get_accounts_pages_through_depositors() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(2)).build());
+        amm.ft_on_transfer(accounts(1), U128(5_000), "".to_string());
+        assert_eq!(amm.get_number_of_accounts(), 2);
+        assert_eq!(
+            amm.get_accounts(0, 10),
+            json!([
+                { "account_id": accounts(0), "token_a": "1000000", "token_b": "1000000", "shares": "0" },
+                { "account_id": accounts(1), "token_a": "105000", "token_b": "100000", "shares": "0" },
+            ])
+            .to_string()
+        );
+        assert_eq!(
+            amm.get_accounts(1, 1),
+            json!([
+                { "account_id": accounts(1), "token_a": "105000", "token_b": "100000", "shares": "0" },
+            ])
+            .to_string()
+        );
+        assert_eq!(amm.get_accounts(2, 10), "[]");
+    }
+
+    #[test]
+    fn test_index_accounts_backfills_registered_accounts() {
+        let mut amm = init_pool();
+        assert_eq!(amm.get_number_of_accounts(), 1);
+        testing_env!(get_context(accounts(0)).build());
+        assert_eq!(amm.index_accounts(vec![accounts(1), accounts(4)]), 1);
+        assert_eq!(amm.index_accounts(vec![accounts(1)]), 0);
+        assert_eq!(amm.get_number_of_accounts(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_index_accounts_by_non_owner() {
+        let mut amm = init_pool();
+        amm.index_accounts(vec![accounts(1)]);
+    }
+
+    #[test]
+    fn test_unregistered_account_leaves_index() {
+        let mut amm = init_pool();
+        register_account(&mut amm.token_a.0, &accounts(4));
+        register_account(&mut amm.token_b.0, &accounts(4));
+        amm.index_account(&accounts(4));
+        testing_env!(get_context(accounts(4)).attached_deposit(1).build());
+
+        assert!(amm.storage_unregister(accounts(2), None));
+        assert_eq!(amm.get_number_of_accounts(), 2);
+        assert!(amm.storage_unregister(accounts(3), None));
+        assert_eq!(amm.get_number_of_accounts(), 1);
+    }
+
+    #[test]
+    fn test_position_reports_share_and_fees() {
+        let mut amm = init_pool();
+        register_account(&mut amm.token_amm, &accounts(1));
+        amm.add_token_to_pool(accounts(2), U128(100_000), None);
+        // The swap fee of 30 stays in the pool, 99% of it for accounts(1)
+        amm.swap(accounts(2), accounts(3), U128(10_000), None);
+
+        let position: serde_json::Value =
+            serde_json::from_str(&amm.get_position(accounts(1))).unwrap();
+        assert_eq!(position["shares"], "99000");
+        assert_eq!(position["share_bps"], 9_900);
+        assert_eq!(position["token_b"], "90000");
+        assert_eq!(position["fees_earned_a"], "0");
+        assert_eq!(position["fees_earned_b"], "29");
+        // 99% of the 1010000 in the reserve of token_b
+        assert_eq!(position["redeemable_b"], "999900");
+
+        // Taking part of the liquidity out keeps the fees earned so far
+        let redeemable_a: U128 = serde_json::from_value(position["redeemable_a"].clone()).unwrap();
+        amm.exclude_token_from_pool(accounts(2), U128(redeemable_a.0 / 2), None);
+        let position: serde_json::Value =
+            serde_json::from_str(&amm.get_position(accounts(1))).unwrap();
+        assert_eq!(position["fees_earned_b"], "29");
+    }
+
+    #[test]
+    fn test_position_counts_staked_and_locked_shares() {
+        let mut amm = init_pool();
+        register_account(&mut amm.token_amm, &accounts(1));
+        amm.add_token_to_pool(accounts(2), U128(100_000), None);
+        testing_env!(get_context(accounts(1)).attached_deposit(STORAGE_DEPOSIT).build());
+        amm.stake_shares(U128(50_000));
+        amm.lock_liquidity(U128(40_000), 1);
+        amm.swap(accounts(2), accounts(3), U128(10_000), None);
+
+        let position: serde_json::Value =
+            serde_json::from_str(&amm.get_position(accounts(1))).unwrap();
+        assert_eq!(position["shares"], "9000");
+        assert_eq!(position["staked_shares"], "50000");
+        assert_eq!(position["locked_shares"], "40000");
+        assert_eq!(position["share_bps"], 9_900);
+        assert_eq!(position["redeemable_b"], "999900");
+        // The same fees as without staking or locking
+        assert_eq!(position["fees_earned_b"], "29");
+
+        testing_env!(get_context(accounts(1)).block_timestamp(1).build());
+        amm.unlock_liquidity(0);
+        amm.unstake_shares(U128(50_000));
+        let position: serde_json::Value =
+            serde_json::from_str(&amm.get_position(accounts(1))).unwrap();
+        assert_eq!(position["shares"], "99000");
+        assert_eq!(position["fees_earned_b"], "29");
+    }
+
+    #[test]
+    fn test_swaps_update_stats() {
+        let mut amm = init_pool();
+        testing_env!(get_context(accounts(1)).block_timestamp(42).build());
+        let bought_b = amm.swap(accounts(3), accounts(2), U128(10_000), None).0;
+        let bought_a = amm.swap(accounts(2), accounts(3), U128(20_000), None).0;
+
+        let stats: serde_json::Value = serde_json::from_str(&amm.get_stats()).unwrap();
+        assert_eq!(stats["volume_a"], (10_000 + bought_a).to_string());
+        assert_eq!(stats["volume_b"], (bought_b + 20_000).to_string());
+        assert_eq!(stats["fees_a"], "30");
+        assert_eq!(stats["fees_b"], "60");
+        assert_eq!(stats["swap_count"], 2);
+        assert_eq!(
+            stats["last_price"],
+            (20_000 * 100_000_000 / bought_a).to_string()
+        );
+        assert_eq!(stats["price_decimals"], 8);
+        assert_eq!(stats["updated_at"], 42);
+    }
+
+    /// Pool of 1_000_000 NEAR and FTB, with accounts(1) registered on both.
+    fn init_near_pool() -> AMM {
+        testing_env!(get_context(accounts(1)).build());
+        let mut amm = AMM::new(accounts(0), "near".parse().unwrap(), accounts(3), None);
+        amm.set_metadata_a(FungibleTokenMetadata {
+            symbol: "NEAR".to_string(),
+            decimals: 24,
+            ..meta_a()
+        });
+        amm.set_metadata_b(meta_b());
+        for token in [&mut amm.token_a.0, &mut amm.token_b.0] {
+            token.internal_deposit(&accounts(0), 1_000_000);
+            register_account(token, &accounts(1));
+        }
+        amm
+    }
+
+    #[test]
+    fn test_deposit_near_and_swap() {
+        let mut amm = init_near_pool();
+        let bought = amm.get_return(accounts(3), "near".parse().unwrap(), U128(10_000));
+        testing_env!(get_context(accounts(1)).attached_deposit(10_000).build());
+        amm.deposit_near(Some(
+            json!({ "swap": { "buy_token_name": accounts(3) } }).to_string(),
+        ));
+        assert_eq!(amm.ft_balance_of("near".parse().unwrap(), accounts(1)).0, 0);
+        assert_eq!(amm.ft_balance_of(accounts(3), accounts(1)), bought);
+    }
+
+    #[test]
+    fn test_withdraw_near_transfers_native() {
+        let mut amm = init_near_pool();
+        testing_env!(get_context(accounts(1)).attached_deposit(10_000).build());
+        amm.deposit_near(None);
+        testing_env!(get_context(accounts(1)).build());
+        amm.withdraw_tokens("near".parse().unwrap(), U128(4_000));
+        assert_eq!(
+            amm.ft_balance_of("near".parse().unwrap(), accounts(1)).0,
+            6_000
+        );
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        assert_eq!(receipts[0].receiver_id, accounts(1));
+        assert!(matches!(
+            receipts[0].actions[0],
+            near_sdk::mock::VmAction::Transfer { deposit: 4_000 }
+        ));
+    }
+
+    #[test]
+    fn test_near_pool_with_real_reserves() {
+        const NEAR: Balance = 1_000_000_000_000_000_000_000_000;
+        const FTB: Balance = 100_000_000;
+        let near: AccountId = "near".parse().unwrap();
+        let mut amm = init_near_pool();
+        register_account(&mut amm.token_amm, &accounts(1));
+        amm.token_b.0.internal_deposit(&accounts(1), 5_000 * FTB);
+        for token in [&mut amm.token_a.0, &mut amm.token_b.0] {
+            register_account(token, &accounts(4));
+        }
+
+        // 1_000 NEAR against 5_000 FTB, 5 FTB per NEAR
+        testing_env!(get_context(accounts(1))
+            .attached_deposit(1_000 * NEAR)
+            .build());
+        amm.deposit_near(None);
+        amm.add_token_to_pool(near.clone(), U128(1_000 * NEAR), None);
+        amm.add_token_to_pool(accounts(3), U128(5_000 * FTB), None);
+
+        let bought = amm.get_return(accounts(3), near.clone(), U128(10 * NEAR));
+        // 50 FTB less the 0.3% fee and the price impact of about 1%
+        assert!(bought.0 > 49 * FTB && bought.0 < 4_940_000_000);
+        testing_env!(get_context(accounts(4))
+            .attached_deposit(10 * NEAR)
+            .build());
+        amm.deposit_near(Some(
+            json!({ "swap": { "buy_token_name": accounts(3) } }).to_string(),
+        ));
+        assert_eq!(amm.ft_balance_of(accounts(3), accounts(4)), bought);
+
+        let position: serde_json::Value =
+            serde_json::from_str(&amm.get_position(accounts(1))).unwrap();
+        assert_eq!(position["share_bps"], 9_999);
+        // Nearly all of the 0.03 NEAR fee
+        let earned: U128 = serde_json::from_value(position["fees_earned_a"].clone()).unwrap();
+        assert!(earned.0 > 29 * NEAR / 1_000 && earned.0 <= 30 * NEAR / 1_000);
+
+        testing_env!(get_context(accounts(1)).build());
+        amm.exclude_token_from_pool(near.clone(), U128(100 * NEAR), None);
+        amm.withdraw_tokens(near, U128(100 * NEAR));
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        assert!(matches!(
+            receipts[0].actions[0],
+            near_sdk::mock::VmAction::Transfer { deposit } if deposit == 100 * NEAR
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "NEAR is deposited with deposit_near")]
+    fn test_ft_on_transfer_from_near_account() {
+        let mut amm = init_near_pool();
+        testing_env!(get_context("near".parse().unwrap()).build());
+        amm.ft_on_transfer(accounts(1), U128(10_000), "".to_string());
     }
 }
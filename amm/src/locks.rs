@@ -0,0 +1,130 @@
+//! Time-locked liquidity.
+//!
+//! Locked `token_amm` shares are moved to an escrow account, so they can't be
+//! burned, transferred or staked before the lock expires. Their value keeps
+//! following the pool, and the owner gets them back with `unlock_liquidity`.
+//!
+//! As for limit orders, the owner pays the storage of its lock and gets it
+//! back on unlock, and locks are indexed per owner.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::{json, Value};
+use near_sdk::{env, near_bindgen, AccountId, Balance};
+
+use crate::utils::FEE_DIVISOR;
+use crate::*;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Lock {
+    pub owner_id: AccountId,
+    pub shares: Balance,
+    /// Block timestamp, in nanoseconds, from which the shares can be unlocked.
+    pub unlock_at: u64,
+}
+
+fn locks_account() -> AccountId {
+    internal_account("locks")
+}
+
+#[near_bindgen]
+impl AMM {
+    /// Locks `shares` of the caller until `unlock_timestamp`, in nanoseconds.
+    /// Returns the lock id. Attach the storage cost of the lock, the rest is
+    /// refunded.
+    #[payable]
+    pub fn lock_liquidity(&mut self, shares: U128, unlock_timestamp: u64) -> u64 {
+        if shares.0 == 0 {
+            panic!("Nothing to lock");
+        }
+        if unlock_timestamp <= env::block_timestamp() {
+            panic!("The unlock time has already passed");
+        }
+        let owner_id = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        let escrow_id = locks_account();
+        register_account(&mut self.token_amm, &escrow_id);
+        self.token_amm
+            .internal_transfer(&owner_id, &escrow_id, shares.0, None);
+
+        let lock_id = self.next_lock_id;
+        self.next_lock_id += 1;
+        self.locks.insert(
+            &lock_id,
+            &Lock {
+                owner_id: owner_id.clone(),
+                shares: shares.0,
+                unlock_at: unlock_timestamp,
+            },
+        );
+        index_insert(&mut self.account_locks, b"k", &owner_id, lock_id);
+        settle_storage(&owner_id, initial_storage, env::attached_deposit());
+        lock_id
+    }
+
+    /// Gives the shares of an expired lock back to its owner.
+    pub fn unlock_liquidity(&mut self, lock_id: u64) -> U128 {
+        let lock = self.locks.get(&lock_id).expect("Lock not found");
+        if lock.owner_id != env::predecessor_account_id() {
+            panic!("Only the owner of the lock can unlock it");
+        }
+        if env::block_timestamp() < lock.unlock_at {
+            panic!("The liquidity is still locked");
+        }
+        let initial_storage = env::storage_usage();
+        self.locks.remove(&lock_id);
+        index_remove(&mut self.account_locks, &lock.owner_id, lock_id);
+        settle_storage(&lock.owner_id, initial_storage, 0);
+        self.token_amm
+            .internal_transfer(&locks_account(), &lock.owner_id, lock.shares, None);
+        U128(lock.shares)
+    }
+
+    pub fn get_locks(&self, from_index: u64, limit: u64) -> String {
+        let locks: Vec<_> = self
+            .locks
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .map(|(lock_id, lock)| lock_json(lock_id, &lock))
+            .collect();
+        json!(locks).to_string()
+    }
+
+    pub fn get_account_locks(&self, account_id: AccountId) -> String {
+        let locks: Vec<_> = index_get(&self.account_locks, &account_id)
+            .into_iter()
+            .map(|lock_id| lock_json(lock_id, &self.locks.get(&lock_id).unwrap()))
+            .collect();
+        json!(locks).to_string()
+    }
+
+    /// Shares the account has in locks, expired ones included.
+    pub(crate) fn locked_shares(&self, account_id: &AccountId) -> Balance {
+        index_get(&self.account_locks, account_id)
+            .into_iter()
+            .map(|lock_id| self.locks.get(&lock_id).unwrap().shares)
+            .sum()
+    }
+
+    /// Total of the locked shares and their share of the pool, in basis points.
+    pub fn get_locked_liquidity(&self) -> String {
+        let locked = self.token_amm.accounts.get(&locks_account()).unwrap_or(0);
+        let total = self.token_amm.total_supply;
+        json!({
+            "locked_shares": U128(locked),
+            "total_shares": U128(total),
+            "locked_bps": (locked * FEE_DIVISOR as Balance).checked_div(total).unwrap_or(0) as u32,
+        })
+        .to_string()
+    }
+}
+
+fn lock_json(lock_id: u64, lock: &Lock) -> Value {
+    json!({
+        "lock_id": lock_id,
+        "owner_id": lock.owner_id,
+        "shares": U128(lock.shares),
+        "unlock_at": lock.unlock_at,
+    })
+}
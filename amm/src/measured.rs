@@ -0,0 +1,172 @@
+//! Deposits of fee-on-transfer and rebasing tokens.
+//!
+//! Such tokens don't deliver the `amount` passed to `ft_on_transfer`, or
+//! change the balance of the AMM on their own. With measured deposits turned
+//! on for a token, every deposit queries the real balance of the AMM first:
+//! the sender is credited with the increase, at most `amount`, and whatever
+//! else changed is moved into or out of the pool reserve, as `sync` does.
+//! The msg of the transfer then runs on the credited amount.
+//!
+//! The increase is only the deposit when nothing else moves the balance
+//! meanwhile, so the deposits of a measured token are serialised: while one
+//! is measured the others are refunded, withdrawals of the token wait for it,
+//! and it waits for the withdrawals already in flight. Deposits and
+//! withdrawals also wait for a `sync` or `skim` in flight. A deposit whose msg
+//! fails leaves the lock behind, it expires after
+//! `MEASURED_DEPOSIT_TIMEOUT` blocks.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde_json;
+use near_sdk::{env, log, near_bindgen, AccountId, Gas, Promise, PromiseResult};
+
+use crate::*;
+
+const GAS_FOR_MEASURED_DEPOSIT: Gas = Gas(15_000_000_000_000);
+
+/// Blocks after which the promise chain of a measured deposit is surely over.
+pub const MEASURED_DEPOSIT_TIMEOUT: u64 = 100;
+
+/// The measured deposit in flight.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct PendingDeposit {
+    pub token_name: AccountId,
+    /// Block height at which the balance was queried.
+    pub opened_at: u64,
+}
+
+impl PendingDeposit {
+    pub fn is_active(&self) -> bool {
+        env::block_height() < self.opened_at + MEASURED_DEPOSIT_TIMEOUT
+    }
+}
+
+#[near_bindgen]
+impl AMM {
+    pub fn set_measured_deposits(&mut self, token_name: AccountId, measured: bool) {
+        self.assert_owner();
+        if is_native(&token_name) {
+            panic!("NEAR deposits are always exact");
+        }
+        if self.account_id_token_a.eq(&token_name) {
+            self.measured_a = measured;
+        } else if self.account_id_token_b.eq(&token_name) {
+            self.measured_b = measured;
+        } else {
+            panic!("Token not supported");
+        }
+    }
+
+    #[private]
+    pub fn measured_deposit_callback(
+        &mut self,
+        token_name: AccountId,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> U128 {
+        self.pending_deposit = None;
+        let real = match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Failed => {
+                log!("Failed to read the token balance, refunding {}", amount.0);
+                return amount;
+            }
+            PromiseResult::Successful(value) => serde_json::from_slice::<U128>(&value).unwrap().0,
+        };
+        let pool_owner_id = env::current_account_id();
+        let token = self.get_token_by_name_as_ref(&token_name);
+        let credited = real.saturating_sub(token.0.total_supply).min(amount.0);
+        if credited < amount.0 {
+            log!("Received {} of the {} sent", credited, amount.0);
+        }
+
+        // The reserve is whatever is left of the real balance
+        let internal = token.0.total_supply + credited;
+        if real > internal {
+            token.0.internal_deposit(&pool_owner_id, real - internal);
+        } else if internal > real {
+            let reserve = token.0.internal_unwrap_balance_of(&pool_owner_id);
+            token.0.internal_withdraw(&pool_owner_id, (internal - real).min(reserve));
+        }
+
+        if credited > 0 {
+            let message = parse_message(&msg);
+            self.internal_on_transfer(&token_name, &sender_id, credited, message);
+        }
+        U128(0)
+    }
+
+    pub(crate) fn is_measured(&self, token_name: &AccountId) -> bool {
+        (self.measured_a && self.account_id_token_a.eq(token_name))
+            || (self.measured_b && self.account_id_token_b.eq(token_name))
+    }
+
+    /// Reads the real balance of the AMM before crediting the deposit.
+    pub(crate) fn measured_deposit(
+        &mut self,
+        token_name: &AccountId,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> Promise {
+        if self
+            .pending_deposit
+            .as_ref()
+            .is_some_and(|pending| pending.is_active())
+        {
+            panic!("Another measured deposit is in flight, try again");
+        }
+        if *self.withdrawals_in_flight(token_name) > 0 {
+            panic!("Withdrawals of {} are in flight, try again", token_name);
+        }
+        if self.is_reconciling() {
+            panic!("The reserves are being reconciled, try again");
+        }
+        self.pending_deposit = Some(PendingDeposit {
+            token_name: token_name.clone(),
+            opened_at: env::block_height(),
+        });
+        ext_ft::ft_balance_of(
+            env::current_account_id(),
+            token_name.clone(),
+            0,
+            GAS_FOR_FT_BALANCE_OF,
+        )
+        .then(ext_self::measured_deposit_callback(
+            token_name.clone(),
+            sender_id,
+            amount,
+            msg,
+            env::current_account_id(),
+            0,
+            GAS_FOR_MEASURED_DEPOSIT,
+        ))
+    }
+
+    /// Counts the `ft_transfer` of a withdrawal until its callback.
+    pub(crate) fn start_withdrawal(&mut self, token_name: &AccountId) {
+        if let Some(pending) = &self.pending_deposit {
+            if pending.is_active() && pending.token_name.eq(token_name) {
+                panic!("A deposit of {} is being measured, try again", token_name);
+            }
+        }
+        if self.is_reconciling() {
+            panic!("The reserves are being reconciled, try again");
+        }
+        *self.withdrawals_in_flight(token_name) += 1;
+    }
+
+    pub(crate) fn end_withdrawal(&mut self, token_name: &AccountId) {
+        let in_flight = self.withdrawals_in_flight(token_name);
+        *in_flight = in_flight.saturating_sub(1);
+    }
+
+    fn withdrawals_in_flight(&mut self, token_name: &AccountId) -> &mut u64 {
+        if self.account_id_token_a.eq(token_name) {
+            &mut self.withdrawals_in_flight_a
+        } else {
+            &mut self.withdrawals_in_flight_b
+        }
+    }
+}
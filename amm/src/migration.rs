@@ -0,0 +1,109 @@
+//! State versioning.
+//!
+//! Borsh has no room for unknown fields, so a contract deployed over an older
+//! state can't read it. Deploy the new code together with a call to `migrate`,
+//! which reads the stored bytes as a `VersionedAMM` and converts them to the
+//! current layout.
+//!
+//! The state starts with its `StateVersion`, the first field of `AMM`, so the
+//! layout is known from the first byte. The first release has no tag: its
+//! state starts with the length of the `b"amm"` prefix of the share token, a
+//! 3. Every change of the layout gets a new `StateVersion`, an `AMMVn` struct
+//! for the layout it replaces and an arm in `VersionedAMM`.
+
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+use near_contract_standards::fungible_token::FungibleToken;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::{env, near_bindgen, AccountId};
+
+use crate::*;
+
+const STATE_KEY: &[u8] = b"STATE";
+
+/// First byte of the state of the first release.
+const FIRST_RELEASE_LEADING_BYTE: u8 = 3;
+
+/// Leading tag of the state. The tag 3 is the first byte of the untagged
+/// first release, the fourth variant must never be stored.
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StateVersion {
+    /// The first release, stored without a version tag.
+    V1,
+    /// Fees, referrals, loans, orders, schedules, farms, locks, staged
+    /// upgrades, measured deposits, the index of the accounts and the fees
+    /// earned by the LPs.
+    V2,
+}
+
+/// Layout of the first release.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct AMMV1 {
+    pub token_amm: FungibleToken,
+    pub token_a: (FungibleToken, Option<FungibleTokenMetadata>),
+    pub token_b: (FungibleToken, Option<FungibleTokenMetadata>),
+    pub account_id_token_a: AccountId,
+    pub account_id_token_b: AccountId,
+}
+
+/// The stored state, in any of the known layouts. Only read once by
+/// `migrate`, so the variants are not boxed.
+#[allow(clippy::large_enum_variant)]
+pub enum VersionedAMM {
+    V1(AMMV1),
+    V2(AMM),
+}
+
+impl VersionedAMM {
+    pub fn read(state: &[u8]) -> Self {
+        match state.first() {
+            Some(&FIRST_RELEASE_LEADING_BYTE) => {
+                Self::V1(AMMV1::try_from_slice(state).expect("Unknown state layout"))
+            }
+            Some(&tag) if tag == StateVersion::V2 as u8 => {
+                Self::V2(AMM::try_from_slice(state).expect("Unknown state layout"))
+            }
+            _ => panic!("Unknown state version"),
+        }
+    }
+
+    /// Converts the state to the current layout, what a layout didn't have
+    /// starts empty or with its default.
+    pub fn into_current(self) -> AMM {
+        match self {
+            Self::V1(old) => {
+                let mut amm = AMM::from_tokens(
+                    old.token_amm,
+                    old.token_a,
+                    old.token_b,
+                    old.account_id_token_a,
+                    old.account_id_token_b,
+                    env::current_account_id(),
+                );
+                // The first release only registered the owner passed to `new`
+                let pool_owner_id = env::current_account_id();
+                register_account(&mut amm.token_amm, &pool_owner_id);
+                register_account(&mut amm.token_a.0, &pool_owner_id);
+                register_account(&mut amm.token_b.0, &pool_owner_id);
+                amm.index_account(&pool_owner_id);
+                amm
+            }
+            Self::V2(amm) => amm,
+        }
+    }
+}
+
+#[near_bindgen]
+impl AMM {
+    /// Converts the stored state to the current layout. The first release had
+    /// no owner, the contract account itself becomes the owner.
+    #[init(ignore_state)]
+    #[private]
+    pub fn migrate() -> Self {
+        let state = env::storage_read(STATE_KEY).expect("There is no state to migrate");
+        VersionedAMM::read(&state).into_current()
+    }
+
+    pub fn get_state_version(&self) -> String {
+        format!("{:?}", self.state_version)
+    }
+}
@@ -0,0 +1,45 @@
+//! Native NEAR as one side of the pool.
+//!
+//! A pool created with `"near"` as a token id holds NEAR itself on that side.
+//! NEAR is deposited by attaching it to `deposit_near` and leaves with a plain
+//! transfer, otherwise it is accounted for like any other token. Its metadata
+//! is set by the owner as usual, with 24 decimals. Flash loans, measured
+//! deposits and the reconciliation of reserves only work with tokens.
+
+use near_sdk::{env, near_bindgen, AccountId};
+
+use crate::*;
+
+/// Token id standing for native NEAR. `near` is a real account, the
+/// registrar of the `.near` names on mainnet, but no token contract, so a
+/// pool never trades a token with this id; `ft_on_transfer` calls coming
+/// from it are rejected all the same. On other networks, check that no
+/// token runs on `near` before creating a pool with it.
+pub const NEAR_TOKEN_ID: &str = "near";
+
+pub(crate) fn is_native(token_name: &AccountId) -> bool {
+    token_name.as_str() == NEAR_TOKEN_ID
+}
+
+#[near_bindgen]
+impl AMM {
+    /// Credits the attached NEAR to the internal balance of the caller. `msg`
+    /// is run afterwards as for `ft_transfer_call` deposits.
+    #[payable]
+    pub fn deposit_near(&mut self, msg: Option<String>) {
+        let amount = env::attached_deposit();
+        if amount == 0 {
+            panic!("Please attach the NEAR to deposit");
+        }
+        let token_name: AccountId = NEAR_TOKEN_ID.parse().unwrap();
+        if !self.has_native_token() {
+            panic!("Token not supported");
+        }
+        let message = msg.as_deref().and_then(parse_message);
+        self.internal_on_transfer(&token_name, &env::predecessor_account_id(), amount, message);
+    }
+
+    pub(crate) fn has_native_token(&self) -> bool {
+        is_native(&self.account_id_token_a) || is_native(&self.account_id_token_b)
+    }
+}
@@ -0,0 +1,198 @@
+//! Limit orders filled against the pool.
+//!
+//! Placing an order moves the sold tokens from the internal balance of the
+//! owner to an escrow account. Anyone can call `execute_orders`; orders that
+//! would now return at least `min_buy_amount` after the keeper bounty are
+//! swapped against the pool, the owner gets the bought tokens and the keeper
+//! `keeper_fee` basis points of them.
+//!
+//! The owner pays the storage of its order, which is refunded once the order
+//! is filled or canceled. Orders are also indexed per owner.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::{json, Value};
+use near_sdk::{env, log, near_bindgen, AccountId, Balance};
+
+use crate::*;
+
+pub const DEFAULT_KEEPER_FEE: u32 = 10;
+/// Hard maximum of the keeper bounty, in basis points of the bought amount.
+pub const MAX_KEEPER_FEE: u32 = 100;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct LimitOrder {
+    pub owner_id: AccountId,
+    pub sell_token_name: AccountId,
+    pub buy_token_name: AccountId,
+    pub sell_amount: Balance,
+    /// Limit price of the order, as the least amount bought for `sell_amount`.
+    pub min_buy_amount: Balance,
+}
+
+fn orders_account() -> AccountId {
+    internal_account("orders")
+}
+
+#[near_bindgen]
+impl AMM {
+    pub fn set_keeper_fee(&mut self, fee: u32) {
+        self.assert_owner();
+        if fee > MAX_KEEPER_FEE {
+            panic!("The keeper fee can't be more than {}", MAX_KEEPER_FEE);
+        }
+        self.keeper_fee = fee;
+    }
+
+    /// Escrows `sell_amount` until it can be sold for at least
+    /// `min_buy_amount`, returns the order id. Attach the storage cost of the
+    /// order, the rest is refunded.
+    #[payable]
+    pub fn place_order(
+        &mut self,
+        sell_token_name: AccountId,
+        buy_token_name: AccountId,
+        sell_amount: U128,
+        min_buy_amount: U128,
+    ) -> u64 {
+        if buy_token_name.eq(&sell_token_name) {
+            panic!("Tokens can't be equals")
+        }
+        let owner_id = env::predecessor_account_id();
+        if !self.get_token_by_name(&buy_token_name).0.accounts.contains_key(&owner_id) {
+            panic!("Please register for the bought token first");
+        }
+
+        let initial_storage = env::storage_usage();
+        let escrow_id = orders_account();
+        register_account(&mut self.token_a.0, &escrow_id);
+        register_account(&mut self.token_b.0, &escrow_id);
+        let token = self.get_token_by_name_as_ref(&sell_token_name);
+        token.0.internal_transfer(&owner_id, &escrow_id, sell_amount.0, None);
+
+        let order_id = self.next_order_id;
+        self.next_order_id += 1;
+        self.orders.insert(
+            &order_id,
+            &LimitOrder {
+                owner_id: owner_id.clone(),
+                sell_token_name,
+                buy_token_name,
+                sell_amount: sell_amount.0,
+                min_buy_amount: min_buy_amount.0,
+            },
+        );
+        index_insert(&mut self.account_orders, b"q", &owner_id, order_id);
+        settle_storage(&owner_id, initial_storage, env::attached_deposit());
+        order_id
+    }
+
+    /// Gives the escrowed tokens back to the owner of the order.
+    pub fn cancel_order(&mut self, order_id: u64) {
+        let order = self.orders.get(&order_id).expect("Order not found");
+        if order.owner_id != env::predecessor_account_id() {
+            panic!("Only the owner of the order can cancel it");
+        }
+        self.remove_order(order_id, &order.owner_id);
+        let token = self.get_token_by_name_as_ref(&order.sell_token_name);
+        token.0.internal_transfer(
+            &orders_account(),
+            &order.owner_id,
+            order.sell_amount,
+            None,
+        );
+    }
+
+    /// Fills the given orders whose limit price is reached, returns the ids
+    /// of the filled ones. The caller has to be registered for both tokens
+    /// to receive the bounty.
+    pub fn execute_orders(&mut self, order_ids: Vec<u64>) -> Vec<u64> {
+        let keeper_id = env::predecessor_account_id();
+        if !self.token_a.0.accounts.contains_key(&keeper_id)
+            || !self.token_b.0.accounts.contains_key(&keeper_id)
+        {
+            panic!("Please register for both tokens first");
+        }
+
+        let escrow_id = orders_account();
+        let mut filled = vec![];
+        for order_id in order_ids {
+            let order = match self.orders.get(&order_id) {
+                Some(order) => order,
+                None => continue,
+            };
+            let quote = self.quote_swap(
+                &order.buy_token_name,
+                &order.sell_token_name,
+                order.sell_amount,
+            );
+            let bounty = calc_fee_cut(quote, self.keeper_fee);
+            if quote == 0 || quote - bounty < order.min_buy_amount {
+                continue;
+            }
+
+            let bought = self.internal_swap(
+                &escrow_id,
+                &order.buy_token_name,
+                &order.sell_token_name,
+                order.sell_amount,
+                None,
+            );
+            let token = self.get_token_by_name_as_ref(&order.buy_token_name);
+            token
+                .0
+                .internal_transfer(&escrow_id, &order.owner_id, bought - bounty, None);
+            if bounty > 0 {
+                token.0.internal_transfer(&escrow_id, &keeper_id, bounty, None);
+            }
+            self.remove_order(order_id, &order.owner_id);
+            log!("Order {} filled for {}", order_id, bought - bounty);
+            filled.push(order_id);
+        }
+        filled
+    }
+
+    pub fn get_order(&self, order_id: u64) -> Option<String> {
+        self.orders
+            .get(&order_id)
+            .map(|order| order_json(order_id, &order).to_string())
+    }
+
+    pub fn get_orders(&self, from_index: u64, limit: u64) -> String {
+        let orders: Vec<_> = self
+            .orders
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .map(|(order_id, order)| order_json(order_id, &order))
+            .collect();
+        json!(orders).to_string()
+    }
+
+    pub fn get_account_orders(&self, account_id: AccountId) -> String {
+        let orders: Vec<_> = index_get(&self.account_orders, &account_id)
+            .into_iter()
+            .map(|order_id| order_json(order_id, &self.orders.get(&order_id).unwrap()))
+            .collect();
+        json!(orders).to_string()
+    }
+
+    /// Removes the order and refunds the storage it used to its owner.
+    fn remove_order(&mut self, order_id: u64, owner_id: &AccountId) {
+        let initial_storage = env::storage_usage();
+        self.orders.remove(&order_id);
+        index_remove(&mut self.account_orders, owner_id, order_id);
+        settle_storage(owner_id, initial_storage, 0);
+    }
+}
+
+fn order_json(order_id: u64, order: &LimitOrder) -> Value {
+    json!({
+        "order_id": order_id,
+        "owner_id": order.owner_id,
+        "sell_token_name": order.sell_token_name,
+        "buy_token_name": order.buy_token_name,
+        "sell_amount": U128(order.sell_amount),
+        "min_buy_amount": U128(order.min_buy_amount),
+    })
+}
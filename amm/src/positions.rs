@@ -0,0 +1,119 @@
+//! Positions of the LPs.
+//!
+//! Swap and flash loan fees stay in the pool, so they are only visible as a
+//! growing share value. To report them, the fees kept by the pool are added
+//! to a fee per share accumulator of each token, and every account remembers
+//! the accumulators it was last settled at. Balances of shares only change
+//! after `settle_lp_fees`, so `earned + shares * (accumulator - settled)` is
+//! what the account earned while holding them. The shares an account staked
+//! or locked are still its own: they count with the shares it holds, so
+//! staking or locking them doesn't need a settlement.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+use near_sdk::{env, near_bindgen, AccountId, Balance};
+
+use crate::utils::{FEE_DIVISOR, U256};
+use crate::*;
+
+const FEE_PRECISION: u128 = 1_000_000_000_000_000_000_000_000;
+
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct LpFees {
+    /// Words of the U256 accumulators of token_a and token_b at the last
+    /// settlement, scaled by `FEE_PRECISION`.
+    pub fees_per_share_a: [u64; 4],
+    pub fees_per_share_b: [u64; 4],
+    pub earned_a: Balance,
+    pub earned_b: Balance,
+}
+
+#[near_bindgen]
+impl AMM {
+    /// Internal deposits and LP shares of the account, held, staked and
+    /// locked. All of them make up its share of the pool, the part of each
+    /// reserve they represent and the fees they earned since the account last
+    /// entered the pool.
+    pub fn get_position(&self, account_id: AccountId) -> String {
+        let shares = self.token_amm.accounts.get(&account_id).unwrap_or(0);
+        let staked = self.get_staked_shares(account_id.clone()).0;
+        let locked = self.locked_shares(&account_id);
+        let all_shares = shares + staked + locked;
+        let total = self.token_amm.total_supply;
+        let (earned_a, earned_b) = self.lp_fees_earned(&account_id, all_shares);
+        json!({
+            "account_id": account_id,
+            "token_a": U128(self.token_a.0.accounts.get(&account_id).unwrap_or(0)),
+            "token_b": U128(self.token_b.0.accounts.get(&account_id).unwrap_or(0)),
+            "shares": U128(shares),
+            "staked_shares": U128(staked),
+            "locked_shares": U128(locked),
+            "share_bps": (all_shares * FEE_DIVISOR as Balance).checked_div(total).unwrap_or(0) as u32,
+            "redeemable_a": U128(self.redeemable(&self.token_a.0, all_shares)),
+            "redeemable_b": U128(self.redeemable(&self.token_b.0, all_shares)),
+            "fees_earned_a": U128(earned_a),
+            "fees_earned_b": U128(earned_b),
+        })
+        .to_string()
+    }
+
+    /// Adds fees kept by the pool to the accumulator of `token_name`.
+    pub(crate) fn accrue_lp_fee(&mut self, token_name: &AccountId, fee: Balance) {
+        let total = self.token_amm.total_supply;
+        if fee == 0 || total == 0 {
+            return;
+        }
+        let increase = U256::from(fee) * U256::from(FEE_PRECISION) / U256::from(total);
+        let fees_per_share = if self.account_id_token_a.eq(token_name) {
+            &mut self.lp_fees_per_share_a
+        } else {
+            &mut self.lp_fees_per_share_b
+        };
+        *fees_per_share = (U256(*fees_per_share) + increase).0;
+    }
+
+    /// Moves the fees earned by the account so far to `earned`, call it
+    /// before its shares change.
+    pub(crate) fn settle_lp_fees(&mut self, account_id: &AccountId) {
+        let shares = self.token_amm.accounts.get(account_id).unwrap_or(0)
+            + self.get_staked_shares(account_id.clone()).0
+            + self.locked_shares(account_id);
+        let (earned_a, earned_b) = self.lp_fees_earned(account_id, shares);
+        self.lp_fees.insert(
+            account_id,
+            &LpFees {
+                fees_per_share_a: self.lp_fees_per_share_a,
+                fees_per_share_b: self.lp_fees_per_share_b,
+                earned_a,
+                earned_b,
+            },
+        );
+    }
+
+    /// An account without shares starts earning from zero again.
+    fn lp_fees_earned(&self, account_id: &AccountId, shares: Balance) -> (Balance, Balance) {
+        if shares == 0 {
+            return (0, 0);
+        }
+        let fees = self.lp_fees.get(account_id).unwrap_or_default();
+        let accrued = |current: [u64; 4], settled: [u64; 4]| {
+            (U256::from(shares) * (U256(current) - U256(settled)) / U256::from(FEE_PRECISION))
+                .as_u128()
+        };
+        (
+            fees.earned_a + accrued(self.lp_fees_per_share_a, fees.fees_per_share_a),
+            fees.earned_b + accrued(self.lp_fees_per_share_b, fees.fees_per_share_b),
+        )
+    }
+
+    /// Part of the reserve of the token the shares represent.
+    fn redeemable(&self, token: &FungibleToken, shares: Balance) -> Balance {
+        let total = self.token_amm.total_supply;
+        if total == 0 {
+            return 0;
+        }
+        let reserve = token.internal_unwrap_balance_of(&env::current_account_id());
+        (U256::from(shares) * U256::from(reserve) / U256::from(total)).as_u128()
+    }
+}
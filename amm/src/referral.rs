@@ -0,0 +1,93 @@
+//! Referral fees for whitelisted front-ends.
+//!
+//! A swap made with the `referral_id` of a whitelisted referrer credits
+//! `referral_fee` basis points of the swap fee to the internal balance of the
+//! referrer, in the sold token.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+use near_sdk::{near_bindgen, AccountId, Balance};
+
+use crate::*;
+
+/// Hard maximum of the referral fee, in basis points of the swap fee.
+pub const MAX_REFERRAL_FEE: u32 = 5_000;
+
+/// Fees a referrer has earned so far.
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct Referrer {
+    pub earned_a: Balance,
+    pub earned_b: Balance,
+}
+
+#[near_bindgen]
+impl AMM {
+    pub fn set_referral_fee(&mut self, fee: u32) {
+        self.assert_owner();
+        if fee > MAX_REFERRAL_FEE {
+            panic!("The referral fee can't be more than {}", MAX_REFERRAL_FEE);
+        }
+        self.referral_fee = fee;
+    }
+
+    pub fn add_referrer(&mut self, referrer_id: AccountId) {
+        self.assert_owner();
+        if self.referrers.get(&referrer_id).is_some() {
+            panic!("The referrer is already registered");
+        }
+        register_account(&mut self.token_a.0, &referrer_id);
+        register_account(&mut self.token_b.0, &referrer_id);
+        self.index_account(&referrer_id);
+        self.referrers.insert(&referrer_id, &Referrer::default());
+    }
+
+    /// Stops paying the referrer, fees already credited stay on its balances.
+    pub fn remove_referrer(&mut self, referrer_id: AccountId) {
+        self.assert_owner();
+        if self.referrers.remove(&referrer_id).is_none() {
+            panic!("The referrer is not registered");
+        }
+    }
+
+    /// Fees earned by the referrer, in token A and B.
+    pub fn get_referral_fees(&self, referrer_id: AccountId) -> (U128, U128) {
+        let referrer = self
+            .referrers
+            .get(&referrer_id)
+            .expect("The referrer is not registered");
+        (U128(referrer.earned_a), U128(referrer.earned_b))
+    }
+
+    pub fn get_referrers(&self, from_index: u64, limit: u64) -> String {
+        let referrers: Vec<_> = self
+            .referrers
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .map(|(referrer_id, referrer)| {
+                json!({
+                    "referrer_id": referrer_id,
+                    "earned_a": U128(referrer.earned_a),
+                    "earned_b": U128(referrer.earned_b),
+                })
+            })
+            .collect();
+        json!(referrers).to_string()
+    }
+
+    pub(crate) fn record_referral_fee(
+        &mut self,
+        referrer_id: &AccountId,
+        token_a: bool,
+        amount: Balance,
+    ) {
+        let mut referrer = self.referrers.get(referrer_id).unwrap();
+        if token_a {
+            referrer.earned_a += amount;
+        } else {
+            referrer.earned_b += amount;
+        }
+        self.referrers.insert(referrer_id, &referrer);
+    }
+}
@@ -0,0 +1,182 @@
+//! Reconciliation of the internal balances with what the AMM really holds on
+//! the token contracts.
+//!
+//! Tokens sent with a plain `ft_transfer`, or rebasing tokens, make the real
+//! balance drift from the sum of the internal balances. `sync` moves the
+//! difference into (or out of) the pool reserves and `skim` hands a surplus to
+//! an account instead. Pools with a NEAR side can't be reconciled, the
+//! balance of the account also pays for storage.
+//!
+//! Measured deposits and withdrawals in flight would show up as drift, so
+//! `sync` and `skim` refuse to start while one is, and new ones are refused
+//! until the reconcile is done or `RECONCILE_TIMEOUT` blocks have passed.
+//! Plain deposits arriving meanwhile still do, so only the owner can
+//! reconcile.
+
+use near_contract_standards::fungible_token::FungibleToken;
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::{self, json, Value};
+use near_sdk::{env, log, near_bindgen, AccountId, Balance, Promise, PromiseResult};
+
+use crate::*;
+
+/// Blocks after which the promise chain of a `sync` or `skim` is surely over.
+pub const RECONCILE_TIMEOUT: u64 = 100;
+
+#[near_bindgen]
+impl AMM {
+    /// Moves the difference between the real and the internal balances of
+    /// both tokens into the pool reserves.
+    pub fn sync(&mut self) -> Promise {
+        self.assert_owner();
+        self.start_reconcile();
+        self.pool_ft_balances().then(ext_self::sync_callback(
+            env::current_account_id(),
+            0,
+            GAS_FOR_CALLBACK,
+        ))
+    }
+
+    /// Credits any surplus of both tokens to the internal balance of `to`,
+    /// registering it when needed.
+    pub fn skim(&mut self, to: AccountId) -> Promise {
+        self.assert_owner();
+        self.start_reconcile();
+        self.pool_ft_balances().then(ext_self::skim_callback(
+            to,
+            env::current_account_id(),
+            0,
+            GAS_FOR_CALLBACK,
+        ))
+    }
+
+    /// Reports the drift of both tokens without changing anything. It has
+    /// to query the token contracts, so it is a call rather than a view; the
+    /// report is the result of the call.
+    pub fn audit_reserves(&self) -> Promise {
+        self.pool_ft_balances()
+            .then(ext_self::audit_reserves_callback(
+                env::current_account_id(),
+                0,
+                GAS_FOR_CALLBACK,
+            ))
+    }
+
+    #[private]
+    pub fn sync_callback(&mut self) {
+        self.reconciling_since = None;
+        let (real_a, real_b) = promised_balances();
+        let pool_owner_id = env::current_account_id();
+        for (token, real) in [(&mut self.token_a.0, real_a), (&mut self.token_b.0, real_b)] {
+            let (surplus, deficit) = drift(token, real);
+            if surplus > 0 {
+                token.internal_deposit(&pool_owner_id, surplus);
+            }
+            if deficit > 0 {
+                let reserve = token.internal_unwrap_balance_of(&pool_owner_id);
+                if deficit > reserve {
+                    log!("Deficit of {} exceeds the pool reserve of {}", deficit, reserve);
+                }
+                token.internal_withdraw(&pool_owner_id, deficit.min(reserve));
+            }
+        }
+    }
+
+    #[private]
+    pub fn skim_callback(&mut self, to: AccountId) {
+        self.reconciling_since = None;
+        let (real_a, real_b) = promised_balances();
+        self.index_account(&to);
+        for (token, real) in [(&mut self.token_a.0, real_a), (&mut self.token_b.0, real_b)] {
+            let (surplus, _) = drift(token, real);
+            if surplus > 0 {
+                register_account(token, &to);
+                token.internal_deposit(&to, surplus);
+            }
+        }
+    }
+
+    #[private]
+    pub fn audit_reserves_callback(&self) -> String {
+        let (real_a, real_b) = promised_balances();
+        json!({
+            "token_a": audit(&self.account_id_token_a, &self.token_a.0, real_a),
+            "token_b": audit(&self.account_id_token_b, &self.token_b.0, real_b),
+        })
+        .to_string()
+    }
+
+    pub(crate) fn is_reconciling(&self) -> bool {
+        self.reconciling_since
+            .is_some_and(|since| env::block_height() < since + RECONCILE_TIMEOUT)
+    }
+
+    /// Holds back measured deposits and withdrawals until the callback.
+    fn start_reconcile(&mut self) {
+        if self.is_reconciling() {
+            panic!("The reserves are being reconciled, try again");
+        }
+        if self
+            .pending_deposit
+            .as_ref()
+            .is_some_and(|pending| pending.is_active())
+        {
+            panic!("A measured deposit is in flight, try again");
+        }
+        if self.withdrawals_in_flight_a > 0 || self.withdrawals_in_flight_b > 0 {
+            panic!("Withdrawals are in flight, try again");
+        }
+        self.reconciling_since = Some(env::block_height());
+    }
+
+    /// Queries the real balances of the AMM on both token contracts.
+    fn pool_ft_balances(&self) -> Promise {
+        if self.has_native_token() {
+            panic!("The reserves of NEAR can't be reconciled");
+        }
+        let pool_owner_id = env::current_account_id();
+        ext_ft::ft_balance_of(
+            pool_owner_id.clone(),
+            self.account_id_token_a.clone(),
+            0,
+            GAS_FOR_FT_BALANCE_OF,
+        )
+        .and(ext_ft::ft_balance_of(
+            pool_owner_id,
+            self.account_id_token_b.clone(),
+            0,
+            GAS_FOR_FT_BALANCE_OF,
+        ))
+    }
+}
+
+/// Surplus and deficit of the real balance against the internal ones.
+fn drift(token: &FungibleToken, real: Balance) -> (Balance, Balance) {
+    (
+        real.saturating_sub(token.total_supply),
+        token.total_supply.saturating_sub(real),
+    )
+}
+
+fn audit(token_name: &AccountId, token: &FungibleToken, real: Balance) -> Value {
+    let (surplus, deficit) = drift(token, real);
+    json!({
+        "account_id": token_name,
+        "internal": U128(token.total_supply),
+        "real": U128(real),
+        "surplus": U128(surplus),
+        "deficit": U128(deficit),
+    })
+}
+
+fn promised_balances() -> (Balance, Balance) {
+    if env::promise_results_count() != 2 {
+        panic!("Expected the balances of both tokens");
+    }
+    let balance = |index| match env::promise_result(index) {
+        PromiseResult::NotReady => unreachable!(),
+        PromiseResult::Failed => panic!("Failed to read the token balances"),
+        PromiseResult::Successful(value) => serde_json::from_slice::<U128>(&value).unwrap().0,
+    };
+    (balance(0), balance(1))
+}
@@ -0,0 +1,87 @@
+//! Swap statistics.
+//!
+//! Counters only ever grow, so volumes and fees over a period are the
+//! difference between two reads of `get_stats`, whose `updated_at` tells when
+//! each was last changed.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+use near_sdk::{env, near_bindgen, Balance};
+
+use crate::utils::{add_decimals, U256};
+use crate::*;
+
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct SwapStats {
+    /// Sold plus bought amounts of each token.
+    pub volume_a: Balance,
+    pub volume_b: Balance,
+    /// Swap fees paid in each token, the protocol and referral parts included.
+    pub fees_a: Balance,
+    pub fees_b: Balance,
+    pub swap_count: u64,
+    /// Amounts of the last swap, which give its price.
+    pub last_amount_a: Balance,
+    pub last_amount_b: Balance,
+    /// Block timestamp, in nanoseconds, of the last swap.
+    pub updated_at: u64,
+}
+
+#[near_bindgen]
+impl AMM {
+    /// Counters of all the swaps so far. `last_price` is the token_b paid or
+    /// received per token_a in the last swap, with `price_decimals`
+    /// fractional digits.
+    pub fn get_stats(&self) -> String {
+        let stats = &self.stats;
+        let (price, decimals) = self.last_price();
+        json!({
+            "volume_a": U128(stats.volume_a),
+            "volume_b": U128(stats.volume_b),
+            "fees_a": U128(stats.fees_a),
+            "fees_b": U128(stats.fees_b),
+            "swap_count": stats.swap_count,
+            "last_price": U128(price),
+            "price_decimals": decimals,
+            "updated_at": stats.updated_at,
+        })
+        .to_string()
+    }
+
+    pub(crate) fn record_swap(
+        &mut self,
+        sells_token_a: bool,
+        sell_amount: Balance,
+        buy_amount: Balance,
+        fee: Balance,
+    ) {
+        let stats = &mut self.stats;
+        let (amount_a, amount_b) = if sells_token_a {
+            stats.fees_a += fee;
+            (sell_amount, buy_amount)
+        } else {
+            stats.fees_b += fee;
+            (buy_amount, sell_amount)
+        };
+        stats.volume_a += amount_a;
+        stats.volume_b += amount_b;
+        stats.swap_count += 1;
+        stats.last_amount_a = amount_a;
+        stats.last_amount_b = amount_b;
+        stats.updated_at = env::block_timestamp();
+    }
+
+    fn last_price(&self) -> (Balance, u8) {
+        if self.stats.swap_count == 0 {
+            return (0, 0);
+        }
+        let decimals_a = self.token_a().unwrap().decimals;
+        let decimals_b = self.token_b().unwrap().decimals;
+        let decimals = max(decimals_a, decimals_b);
+        let a = add_decimals(self.stats.last_amount_a, decimals - decimals_a);
+        let b = add_decimals(self.stats.last_amount_b, decimals - decimals_b);
+        let price = U256::from(b) * U256::from(10_u128.pow(decimals as u32)) / U256::from(a);
+        (price.as_u128(), decimals)
+    }
+}
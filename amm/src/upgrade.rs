@@ -0,0 +1,114 @@
+//! Upgrades of the contract code in place.
+//!
+//! The owner stages the new code first; it can only be deployed once
+//! `upgrade_delay` seconds have passed, so LPs have time to leave the pool if
+//! they don't like it. The delay can only grow. The deploy is chained with a
+//! `migrate` call that converts the state, see `migration`.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::Base58CryptoHash;
+use near_sdk::serde_json::json;
+use near_sdk::{env, log, near_bindgen, Gas, Promise};
+
+use crate::*;
+
+/// Storage key of the staged code, kept out of the state struct so that the
+/// whole wasm isn't read on every call.
+const STAGED_CODE_KEY: &[u8] = b"upgrade-code";
+const NANOSECONDS_IN_SECOND: u64 = 1_000_000_000;
+
+/// Delay of a new pool, in seconds, two days for LPs to react to staged code.
+pub const DEFAULT_UPGRADE_DELAY: u64 = 2 * 24 * 60 * 60;
+/// Hard maximum of the delay, in seconds. The delay can't be shortened, so
+/// without a bound it could block the upgrades for good.
+pub const MAX_UPGRADE_DELAY: u64 = 30 * 24 * 60 * 60;
+
+/// Gas kept for the `upgrade` call itself.
+const GAS_FOR_UPGRADE: Gas = Gas(20_000_000_000_000);
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct StagedUpgrade {
+    pub code_hash: [u8; 32],
+    /// Block timestamp, in nanoseconds, from which the code can be deployed.
+    pub deployable_at: u64,
+}
+
+#[near_bindgen]
+impl AMM {
+    /// Stages the wasm passed as the raw input of the call, replacing the
+    /// code staged before.
+    pub fn stage_upgrade(&mut self) {
+        self.assert_owner();
+        let code = env::input().expect("Please pass the wasm code as the input");
+        let code_hash = env::sha256_array(&code);
+        env::storage_write(STAGED_CODE_KEY, &code);
+        self.staged_upgrade = Some(StagedUpgrade {
+            code_hash,
+            deployable_at: env::block_timestamp() + self.upgrade_delay * NANOSECONDS_IN_SECOND,
+        });
+        log!(
+            "Staged code {}",
+            String::from(&Base58CryptoHash::from(code_hash))
+        );
+    }
+
+    pub fn cancel_upgrade(&mut self) {
+        self.assert_owner();
+        if self.staged_upgrade.take().is_none() {
+            panic!("There is no staged upgrade");
+        }
+        env::storage_remove(STAGED_CODE_KEY);
+    }
+
+    /// Deploys the staged code to this account and migrates the state.
+    pub fn upgrade(&mut self) -> Promise {
+        self.assert_owner();
+        let staged = self
+            .staged_upgrade
+            .take()
+            .expect("There is no staged upgrade");
+        if env::block_timestamp() < staged.deployable_at {
+            panic!(
+                "The upgrade can't be deployed before {}",
+                staged.deployable_at
+            );
+        }
+        if env::prepaid_gas() < GAS_FOR_UPGRADE + env::used_gas() {
+            panic!("Not enough gas attached");
+        }
+        let code = env::storage_read(STAGED_CODE_KEY).unwrap();
+        env::storage_remove(STAGED_CODE_KEY);
+
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call(
+                "migrate".to_string(),
+                b"{}".to_vec(),
+                0,
+                env::prepaid_gas() - env::used_gas() - GAS_FOR_UPGRADE,
+            )
+    }
+
+    /// Sets the delay between staging and deploying new code, in seconds.
+    pub fn set_upgrade_delay(&mut self, delay: u64) {
+        self.assert_owner();
+        if delay < self.upgrade_delay {
+            panic!("The upgrade delay can't be shortened");
+        }
+        if delay > MAX_UPGRADE_DELAY {
+            panic!("The upgrade delay can't be more than {}", MAX_UPGRADE_DELAY);
+        }
+        self.upgrade_delay = delay;
+    }
+
+    pub fn get_upgrade(&self) -> String {
+        json!({
+            "upgrade_delay": self.upgrade_delay,
+            "staged": self.staged_upgrade.as_ref().map(|staged| json!({
+                "code_hash": Base58CryptoHash::from(staged.code_hash),
+                "deployable_at": staged.deployable_at,
+            })),
+        })
+        .to_string()
+    }
+}
@@ -1,25 +1,96 @@
+//! Pool arithmetic.
+//!
+//! Rounding always favours the pool: amounts paid out (swap outputs, minted
+//! shares) round down and amounts the pool requires in return (shares burned
+//! on withdrawal) round up, so the dust of every operation stays with the LPs.
+
 use near_sdk::Balance;
 
+pub use u256::U256;
+
+/// Fees are expressed in basis points of this divisor.
+pub const FEE_DIVISOR: u32 = 10_000;
+
 pub fn add_decimals(value: Balance, decimals: u8) -> Balance {
     value * 10_u128.pow(decimals as u32)
 }
 
+/// Rounds down.
+#[cfg(test)]
 pub fn remove_decimals(value: Balance, decimals: u8) -> Balance {
     value / 10_u128.pow(decimals as u32)
 }
 
+/// Output of selling `amount` of a token with reserve `x` for a token with
+/// reserve `y`. Both are in the decimals of their own token: bringing them
+/// to the same decimals scales `x` and `amount` alike, and `y` and the output
+/// alike, so it doesn't change the result. Rounds down.
 pub fn calc_dy(x: Balance, y: Balance, amount: Balance) -> Balance {
-    y - (x * y / (x + amount))
+    mul_div(y, amount, U256::from(x) + U256::from(amount))
+}
+
+/// Shares minted for adding `amount` to a pool worth `pool_value` with
+/// `total_shares` outstanding. Rounds down.
+pub fn calc_shares(amount: Balance, pool_value: Balance, total_shares: Balance) -> Balance {
+    if total_shares == 0 || pool_value == 0 {
+        amount
+    } else {
+        mul_div(amount, total_shares, U256::from(pool_value))
+    }
+}
+
+/// Shares burned for taking `amount` out of a pool worth `pool_value` with
+/// `total_shares` outstanding. Rounds up.
+pub fn calc_burn_shares(amount: Balance, pool_value: Balance, total_shares: Balance) -> Balance {
+    if amount == 0 {
+        return 0;
+    }
+    if pool_value == 0 {
+        panic!("The pool is empty");
+    }
+    mul_div_ceil(amount, total_shares, U256::from(pool_value))
+}
+
+/// `fee` basis points of `amount`. Rounds up, fees are paid to the pool.
+pub fn calc_fee(amount: Balance, fee: u32) -> Balance {
+    mul_div_ceil(amount, fee as Balance, U256::from(FEE_DIVISOR))
+}
+
+/// Part `share` (in basis points) of a collected fee that leaves the pool.
+/// Rounds down.
+pub fn calc_fee_cut(fee_amount: Balance, share: u32) -> Balance {
+    mul_div(fee_amount, share as Balance, U256::from(FEE_DIVISOR))
 }
 
+/// `a / b` with `decimals` fractional digits. Rounds down.
 pub fn calc_raito(a: Balance, b: Balance, decimals: u8) -> Balance {
-    remove_decimals(add_decimals(a, decimals + 1) / b, 1)
+    add_decimals(a, decimals) / b
+}
+
+/// `a * b / c` without overflowing the product. The result has to fit a
+/// `Balance`. Rounds down.
+fn mul_div(a: Balance, b: Balance, c: U256) -> Balance {
+    (U256::from(a) * U256::from(b) / c).as_u128()
+}
+
+/// `a * b / c` without overflowing the product. Rounds up.
+fn mul_div_ceil(a: Balance, b: Balance, c: U256) -> Balance {
+    let product = U256::from(a) * U256::from(b);
+    let quotient = product / c;
+    if (quotient * c) == product {
+        quotient.as_u128()
+    } else {
+        (quotient + 1).as_u128()
+    }
 }
 
 #[cfg(not(target_arch = "wasm32"))]
 #[cfg(test)]
+#[allow(clippy::inconsistent_digit_grouping, clippy::zero_prefixed_literal)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
+
     #[test]
     fn test_add_decimals() {
         let decimals = add_decimals(86, 3);
@@ -39,7 +110,7 @@ mod tests {
         let x = 10;
         let y = 20;
         let dy = calc_dy(x, y, 2);
-        assert_eq!(dy, 4);
+        assert_eq!(dy, 3);
 
         let x = 10_000;
         let y = 20_0;
@@ -53,6 +124,27 @@ mod tests {
         assert_eq!(remove_decimals(dy, 1), 0_313_916_98);
     }
 
+    #[test]
+    fn test_calc_dy_with_different_decimals() {
+        // 1 FTA (2 decimals) sold into a 100 FTA / 200 FTB (4 decimals) pool.
+        let dy = calc_dy(100_00, 200_0000, 1_00);
+        assert_eq!(dy, 1_9801);
+    }
+
+    #[test]
+    fn test_calc_shares_rounding() {
+        assert_eq!(calc_shares(1_000, 0, 0), 1_000);
+        assert_eq!(calc_shares(10, 3_000, 1_000), 3);
+        assert_eq!(calc_burn_shares(10, 3_000, 1_000), 4);
+    }
+
+    #[test]
+    fn test_calc_fee_rounding() {
+        assert_eq!(calc_fee(10_000, 30), 30);
+        assert_eq!(calc_fee(10_001, 30), 31);
+        assert_eq!(calc_fee_cut(31, 1_667), 5);
+    }
+
     #[test]
     fn test_calc_ratio_with_two_decimal() {
         let x = 4_000;
@@ -63,4 +155,71 @@ mod tests {
         let y = 4_000;
         assert_eq!(2_00, calc_raito(x, y, 2));
     }
+
+    #[test]
+    fn test_calc_with_24_decimals() {
+        // 1_000_000 NEAR against 10 NEAR worth of a 24 decimals token
+        let x = 1_000_000_000000000000000000000000;
+        let y = 10_000000000000000000000000;
+        assert_eq!(calc_dy(x, y, x), 5_000000000000000000000000);
+        assert_eq!(calc_shares(x, x, y), y);
+        assert_eq!(calc_burn_shares(x / 2, x, y), y / 2);
+        assert_eq!(calc_fee(x, 30), 3_000_000000000000000000000000);
+        assert_eq!(calc_fee_cut(x, 1_000), x / 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "The pool is empty")]
+    fn test_calc_burn_shares_of_empty_pool() {
+        calc_burn_shares(10, 0, 1_000);
+    }
+
+    proptest! {
+        #[test]
+        fn k_never_decreases(
+            x in 1_000..1_000_000_000_000_000_000_000_000_000_000_000_u128,
+            y in 1_000..1_000_000_000_000_000_000_000_000_000_000_000_u128,
+            fee in 0..1_000_u32,
+            protocol_fee in 0..5_000_u32,
+            swaps in prop::collection::vec(
+                (any::<bool>(), 1..1_000_000_000_000_000_000_000_000_000_000_u128),
+                1..32,
+            ),
+        ) {
+            let (mut x, mut y) = (x, y);
+            for (sell_x, amount) in swaps {
+                let k = U256::from(x) * U256::from(y);
+                let fee_amount = calc_fee(amount, fee);
+                let amount_in = amount - calc_fee_cut(fee_amount, protocol_fee);
+                if sell_x {
+                    let dy = calc_dy(x, y, amount - fee_amount);
+                    prop_assert!(dy < y);
+                    x += amount_in;
+                    y -= dy;
+                } else {
+                    let dx = calc_dy(y, x, amount - fee_amount);
+                    prop_assert!(dx < x);
+                    y += amount_in;
+                    x -= dx;
+                }
+                prop_assert!(U256::from(x) * U256::from(y) >= k);
+            }
+        }
+
+        #[test]
+        fn add_then_exclude_never_profits(
+            pool_value in 1..1_000_000_000_000_000_000_000_000_000_000_000_000_u128,
+            total_shares in 1..1_000_000_000_000_000_000_000_000_000_000_000_000_u128,
+            amount in 1..1_000_000_000_000_000_000_000_000_000_000_000_000_u128,
+        ) {
+            // The minted shares have to fit a `Balance`
+            prop_assume!(
+                U256::from(amount) * U256::from(total_shares) / U256::from(pool_value)
+                    < U256::from(u128::MAX / 2)
+            );
+            let minted = calc_shares(amount, pool_value, total_shares);
+            let burned = calc_burn_shares(amount, pool_value + amount, total_shares + minted);
+            prop_assert!(burned >= minted);
+        }
+    }
 }
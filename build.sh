@@ -2,5 +2,16 @@
 TARGET="${CARGO_TARGET_DIR:-target}"
 set -e
 cd "`dirname $0`"
-cargo build --workspace --target wasm32-unknown-unknown --release
-cp $TARGET/wasm32-unknown-unknown/release/*.wasm ./res/
+# near-sdk-sim runs an older VM that only knows the MVP features of wasm, the
+# standard library is rebuilt for the MVP too (rustup component add rust-src)
+export RUSTFLAGS="-C target-cpu=mvp"
+export RUSTC_BOOTSTRAP=1
+BUILD="cargo build -Zbuild-std=std,panic_abort --target wasm32-unknown-unknown --release"
+# The taxed token is only used by the tests, build it before the plain one overwrites ft.wasm
+$BUILD -p ft --features ft/taxed
+cp $TARGET/wasm32-unknown-unknown/release/ft.wasm ./res/ft_taxed.wasm
+# The factory embeds res/amm.wasm, so the AMM has to be copied there first
+$BUILD -p ft -p amm
+cp $TARGET/wasm32-unknown-unknown/release/ft.wasm $TARGET/wasm32-unknown-unknown/release/amm.wasm ./res/
+$BUILD -p factory
+cp $TARGET/wasm32-unknown-unknown/release/factory.wasm ./res/
@@ -0,0 +1,338 @@
+//! Factory deploying one AMM per pair of tokens.
+//!
+//! Every pool lives on its own sub-account `pool-<N>.<factory>` running the
+//! AMM code embedded below, owned by the owner of the factory. The caller of
+//! `create_pool` pays for the storage of the new account and for its
+//! registration on both token contracts.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, Vector};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
+use near_sdk::{
+    env, ext_contract, log, near_bindgen, AccountId, Balance, Gas, PanicOnDefault, Promise,
+    PromiseResult,
+};
+
+/// Code of the pools, build it with build.sh before the factory.
+const AMM_CODE: &[u8] = include_bytes!("../../res/amm.wasm");
+
+/// Balance of a new pool account, covers the storage of the AMM code.
+const POOL_STORAGE_BALANCE: Balance = 5_000_000_000_000_000_000_000_000;
+
+/// Token id of native NEAR in the AMM, which needs no registration. See
+/// `NEAR_TOKEN_ID` of the AMM on why it is safe to use a real account id.
+const NEAR_TOKEN_ID: &str = "near";
+
+const GAS_FOR_NEW: Gas = Gas(20_000_000_000_000);
+const GAS_FOR_STORAGE_DEPOSIT: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_CALLBACK: Gas = Gas(30_000_000_000_000);
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Pool {
+    pub pool_id: AccountId,
+    pub token_a: AccountId,
+    pub token_b: AccountId,
+    pub swap_fee: Option<u32>,
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct Factory {
+    owner_id: AccountId,
+    pools: Vector<Pool>,
+    /// Index of the next pool account, pools still being created included.
+    next_pool_index: u64,
+    /// Pool of each pair, keyed by the sorted token ids, pools still being
+    /// created included.
+    pairs: LookupMap<(AccountId, AccountId), AccountId>,
+}
+
+#[ext_contract(ext_self)]
+trait SelfContract {
+    fn on_create_pool(
+        &mut self,
+        pool: Pool,
+        creator_id: AccountId,
+        deposit: U128,
+    ) -> Option<AccountId>;
+}
+
+#[ext_contract(ext_ft)]
+trait FtContract {
+    fn storage_deposit(&mut self, account_id: Option<AccountId>, registration_only: Option<bool>);
+}
+
+/// Key of a pair in `pairs`, the same for both orders of the tokens.
+fn pair_key(token_a: &AccountId, token_b: &AccountId) -> (AccountId, AccountId) {
+    if token_a < token_b {
+        (token_a.clone(), token_b.clone())
+    } else {
+        (token_b.clone(), token_a.clone())
+    }
+}
+
+/// Storage deposit of an account on a token contract.
+fn token_storage_deposit() -> Balance {
+    env::storage_byte_cost() * 125
+}
+
+#[near_bindgen]
+impl Factory {
+    #[init]
+    pub fn new(owner_id: AccountId) -> Self {
+        assert!(!env::state_exists(), "Already initialized");
+        Self {
+            owner_id,
+            pools: Vector::new(b"p".to_vec()),
+            next_pool_index: 0,
+            pairs: LookupMap::new(b"r".to_vec()),
+        }
+    }
+
+    /// Deploys an AMM for the pair on a new sub-account, with the default
+    /// swap fee when `fee` is not given. The pool is registered once it is
+    /// created, a failed creation refunds the deposit. There is one pool per
+    /// pair, whatever the order of the tokens.
+    #[payable]
+    pub fn create_pool(
+        &mut self,
+        token_a: AccountId,
+        token_b: AccountId,
+        fee: Option<u32>,
+    ) -> Promise {
+        if token_a == token_b {
+            panic!("Same token is not acceptable");
+        }
+        let pair = pair_key(&token_a, &token_b);
+        if let Some(pool_id) = self.pairs.get(&pair) {
+            panic!("The pair already has the pool {}", pool_id);
+        }
+        let deposit = env::attached_deposit();
+        let required = POOL_STORAGE_BALANCE + 2 * token_storage_deposit();
+        if deposit < required {
+            panic!("Please attach at least {} yoctoNEAR", required);
+        }
+
+        let pool_id: AccountId = format!(
+            "pool-{}.{}",
+            self.next_pool_index,
+            env::current_account_id()
+        )
+        .parse()
+        .unwrap();
+        self.next_pool_index += 1;
+        self.pairs.insert(&pair, &pool_id);
+        let args = json!({
+            "owner_id": self.owner_id,
+            "token_a_id": token_a,
+            "token_b_id": token_b,
+            "swap_fee": fee,
+        });
+
+        Promise::new(pool_id.clone())
+            .create_account()
+            .transfer(deposit - 2 * token_storage_deposit())
+            .deploy_contract(AMM_CODE.to_vec())
+            .function_call(
+                "new".to_string(),
+                args.to_string().into_bytes(),
+                0,
+                GAS_FOR_NEW,
+            )
+            .then(ext_self::on_create_pool(
+                Pool {
+                    pool_id,
+                    token_a,
+                    token_b,
+                    swap_fee: fee,
+                },
+                env::predecessor_account_id(),
+                U128(deposit),
+                env::current_account_id(),
+                0,
+                GAS_FOR_CALLBACK,
+            ))
+    }
+
+    /// Registers the new pool on both tokens and in the registry, returns
+    /// its account or `None` when the creation failed.
+    #[private]
+    pub fn on_create_pool(
+        &mut self,
+        pool: Pool,
+        creator_id: AccountId,
+        deposit: U128,
+    ) -> Option<AccountId> {
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => {
+                for token in [&pool.token_a, &pool.token_b] {
+                    if token.as_str() == NEAR_TOKEN_ID {
+                        continue;
+                    }
+                    ext_ft::storage_deposit(
+                        Some(pool.pool_id.clone()),
+                        Some(true),
+                        token.clone(),
+                        token_storage_deposit(),
+                        GAS_FOR_STORAGE_DEPOSIT,
+                    );
+                }
+                self.pools.push(&pool);
+                Some(pool.pool_id)
+            }
+            PromiseResult::Failed => {
+                log!("Failed to create {}, refunding {}", pool.pool_id, deposit.0);
+                self.pairs.remove(&pair_key(&pool.token_a, &pool.token_b));
+                Promise::new(creator_id).transfer(deposit.0);
+                None
+            }
+        }
+    }
+
+    pub fn get_number_of_pools(&self) -> u64 {
+        self.pools.len()
+    }
+
+    /// Pool of the pair, in either order, pools still being created included.
+    pub fn get_pool(&self, token_a: AccountId, token_b: AccountId) -> Option<AccountId> {
+        self.pairs.get(&pair_key(&token_a, &token_b))
+    }
+
+    pub fn get_pools(&self, from_index: u64, limit: u64) -> String {
+        let pools: Vec<_> = (from_index..self.pools.len().min(from_index.saturating_add(limit)))
+            .map(|index| self.pools.get(index).unwrap())
+            .collect();
+        json!(pools).to_string()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, RuntimeFeesConfig, VMConfig};
+
+    use super::*;
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    fn with_promise_result(context: &VMContextBuilder, promise_result: PromiseResult) {
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![promise_result]
+        );
+    }
+
+    #[test]
+    fn test_create_pool_registers_pool() {
+        let deposit = POOL_STORAGE_BALANCE + 2 * token_storage_deposit();
+        testing_env!(get_context(accounts(1)).attached_deposit(deposit).build());
+        let mut factory = Factory::new(accounts(0));
+        factory.create_pool(accounts(2), accounts(3), Some(10));
+        factory.create_pool(accounts(3), accounts(4), None);
+        assert_eq!(factory.get_number_of_pools(), 0);
+        assert_eq!(
+            factory.get_pool(accounts(3), accounts(2)),
+            Some("pool-0.alice".parse().unwrap())
+        );
+
+        with_promise_result(&get_context(accounts(0)), PromiseResult::Successful(vec![]));
+        let pool_id: AccountId = "pool-0.alice".parse().unwrap();
+        assert_eq!(
+            factory.on_create_pool(
+                Pool {
+                    pool_id: pool_id.clone(),
+                    token_a: accounts(2),
+                    token_b: accounts(3),
+                    swap_fee: Some(10),
+                },
+                accounts(1),
+                U128(deposit)
+            ),
+            Some(pool_id)
+        );
+        assert_eq!(
+            factory.get_pools(0, 10),
+            json!([{
+                "pool_id": "pool-0.alice",
+                "token_a": accounts(2),
+                "token_b": accounts(3),
+                "swap_fee": 10,
+            }])
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_failed_pool_is_not_registered() {
+        let deposit = POOL_STORAGE_BALANCE + 2 * token_storage_deposit();
+        testing_env!(get_context(accounts(1)).attached_deposit(deposit).build());
+        let mut factory = Factory::new(accounts(0));
+        factory.create_pool(accounts(2), accounts(3), None);
+        with_promise_result(&get_context(accounts(0)), PromiseResult::Failed);
+        let pool = Pool {
+            pool_id: "pool-0.alice".parse().unwrap(),
+            token_a: accounts(2),
+            token_b: accounts(3),
+            swap_fee: None,
+        };
+        let result = factory.on_create_pool(pool, accounts(1), U128(1));
+        assert!(result.is_none());
+        assert_eq!(factory.get_number_of_pools(), 0);
+        assert!(factory.get_pool(accounts(2), accounts(3)).is_none());
+
+        // The pair can be created again
+        testing_env!(get_context(accounts(1)).attached_deposit(deposit).build());
+        factory.create_pool(accounts(3), accounts(2), None);
+        assert_eq!(
+            factory.get_pool(accounts(2), accounts(3)),
+            Some("pool-1.alice".parse().unwrap())
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The pair already has the pool pool-0.alice")]
+    fn test_create_pool_twice() {
+        let deposit = POOL_STORAGE_BALANCE + 2 * token_storage_deposit();
+        testing_env!(get_context(accounts(1)).attached_deposit(deposit).build());
+        let mut factory = Factory::new(accounts(0));
+        factory.create_pool(accounts(2), accounts(3), None);
+        factory.create_pool(accounts(3), accounts(2), Some(10));
+    }
+
+    #[test]
+    fn test_amm_code_is_current() {
+        assert!(AMM_CODE.starts_with(b"\0asm"));
+        // Methods of the latest AMM, an older res/amm.wasm lacks them
+        for method in ["migrate", "index_accounts", "clear_flash_loan"] {
+            assert!(
+                AMM_CODE
+                    .windows(method.len())
+                    .any(|bytes| bytes == method.as_bytes()),
+                "res/amm.wasm has no {}, rebuild it with build.sh",
+                method
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Please attach at least")]
+    fn test_create_pool_without_deposit() {
+        testing_env!(get_context(accounts(1)).build());
+        let mut factory = Factory::new(accounts(0));
+        factory.create_pool(accounts(2), accounts(3), None);
+    }
+}
@@ -0,0 +1,85 @@
+//! Allowances, for integrations used to ERC-20 approve and transferFrom.
+//!
+//! An owner lets a spender move up to `amount` of its tokens with
+//! `ft_approve`, and pays the storage of the allowance. The spender then moves
+//! them with `ft_transfer_from`.
+
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+use near_sdk::{assert_one_yocto, env, near_bindgen, AccountId};
+
+use crate::*;
+
+#[near_bindgen]
+impl FTContract {
+    /// Sets the allowance of `spender_id` over the tokens of the caller, a
+    /// zero `amount` revokes it. Attach the storage cost of a new allowance,
+    /// or at least 1 yoctoNEAR; the rest and any freed storage is refunded.
+    #[payable]
+    pub fn ft_approve(&mut self, spender_id: AccountId, amount: U128) {
+        assert!(
+            env::attached_deposit() > 0,
+            "Requires attached deposit of at least 1 yoctoNEAR"
+        );
+        let owner_id = env::predecessor_account_id();
+        assert!(owner_id != spender_id, "The owner can't approve itself");
+        assert!(
+            self.token.accounts.contains_key(&owner_id),
+            "The account {} is not registered",
+            owner_id
+        );
+
+        let initial_storage = env::storage_usage();
+        let key = (owner_id.clone(), spender_id.clone());
+        if amount.0 == 0 {
+            self.allowances.remove(&key);
+        } else {
+            self.allowances.insert(&key, &amount.0);
+        }
+        settle_storage(&owner_id, initial_storage, env::attached_deposit());
+        emit_event(
+            "ft_approve",
+            json!({ "owner_id": owner_id, "spender_id": spender_id, "amount": amount }),
+        );
+    }
+
+    pub fn ft_allowance(&self, owner_id: AccountId, spender_id: AccountId) -> U128 {
+        U128(self.allowances.get(&(owner_id, spender_id)).unwrap_or(0))
+    }
+
+    /// Moves `amount` of the tokens of `owner_id` to `receiver_id` out of the
+    /// allowance of the caller.
+    #[payable]
+    pub fn ft_transfer_from(
+        &mut self,
+        owner_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+    ) {
+        assert_one_yocto();
+        let spender_id = env::predecessor_account_id();
+        self.assert_can_transfer(&owner_id, &receiver_id);
+        assert!(
+            !self.is_frozen(spender_id.clone()),
+            "The account {} is frozen",
+            spender_id
+        );
+
+        let key = (owner_id.clone(), spender_id);
+        let allowance = self.allowances.get(&key).unwrap_or(0);
+        assert!(
+            amount.0 <= allowance,
+            "The allowance of {} is too low",
+            allowance
+        );
+        let initial_storage = env::storage_usage();
+        if amount.0 == allowance {
+            self.allowances.remove(&key);
+        } else {
+            self.allowances.insert(&key, &(allowance - amount.0));
+        }
+        settle_storage(&owner_id, initial_storage, 0);
+        self.internal_transfer(&owner_id, &receiver_id, amount.0, memo);
+    }
+}
@@ -0,0 +1,72 @@
+//! Pause and freeze controls of the owner.
+//!
+//! While the token is paused no transfer goes through, and a frozen account
+//! can neither send nor receive tokens. Refunds of `ft_transfer_call` still
+//! reach a sender frozen in the meantime, so no tokens get lost.
+
+use near_sdk::serde_json::json;
+use near_sdk::{assert_one_yocto, near_bindgen, AccountId};
+
+use crate::*;
+
+#[near_bindgen]
+impl FTContract {
+    #[payable]
+    pub fn pause(&mut self) {
+        assert_one_yocto();
+        self.assert_owner();
+        assert!(!self.paused, "The token is already paused");
+        self.paused = true;
+        emit_event("ft_pause", json!({}));
+    }
+
+    #[payable]
+    pub fn unpause(&mut self) {
+        assert_one_yocto();
+        self.assert_owner();
+        assert!(self.paused, "The token is not paused");
+        self.paused = false;
+        emit_event("ft_unpause", json!({}));
+    }
+
+    #[payable]
+    pub fn freeze(&mut self, account_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        assert!(
+            self.frozen.insert(&account_id),
+            "The account is already frozen"
+        );
+        emit_event("ft_freeze", json!({ "account_id": account_id }));
+    }
+
+    #[payable]
+    pub fn unfreeze(&mut self, account_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        assert!(self.frozen.remove(&account_id), "The account is not frozen");
+        emit_event("ft_unfreeze", json!({ "account_id": account_id }));
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn is_frozen(&self, account_id: AccountId) -> bool {
+        self.frozen.contains(&account_id)
+    }
+
+    pub(crate) fn assert_can_transfer(&self, sender_id: &AccountId, receiver_id: &AccountId) {
+        assert!(!self.paused, "The token is paused");
+        assert!(
+            !self.frozen.contains(sender_id),
+            "The account {} is frozen",
+            sender_id
+        );
+        assert!(
+            !self.frozen.contains(receiver_id),
+            "The account {} is frozen",
+            receiver_id
+        );
+    }
+}
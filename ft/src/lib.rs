@@ -1,17 +1,79 @@
+use near_contract_standards::fungible_token::core::FungibleTokenCore;
+use near_contract_standards::fungible_token::events::{FtBurn, FtMint};
 use near_contract_standards::fungible_token::metadata::{
     FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC,
 };
+use near_contract_standards::fungible_token::resolver::FungibleTokenResolver;
 use near_contract_standards::fungible_token::FungibleToken;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::LazyOption;
-use near_sdk::json_types::U128;
-use near_sdk::{env, log, near_bindgen, AccountId, Balance, PanicOnDefault, PromiseOrValue};
+use near_sdk::collections::{LazyOption, LookupMap, LookupSet, UnorderedMap, Vector};
+use near_sdk::json_types::{Base64VecU8, U128};
+use near_sdk::serde_json::{json, Value};
+use near_sdk::{
+    assert_one_yocto, env, log, near_bindgen, AccountId, Balance, PanicOnDefault, Promise,
+    PromiseOrValue, StorageUsage,
+};
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct FTContract {
     token: FungibleToken,
     metadata: LazyOption<FungibleTokenMetadata>,
+    /// Account allowed to mint and burn tokens.
+    owner_id: AccountId,
+    /// The total supply can never grow above it when set.
+    max_supply: Option<Balance>,
+    /// No transfer goes through while set.
+    paused: bool,
+    frozen: LookupSet<AccountId>,
+    /// Tokens a spender can still move, by owner and spender.
+    allowances: LookupMap<(AccountId, AccountId), Balance>,
+    grants: UnorderedMap<u64, Grant>,
+    /// Ids of the grants of every beneficiary, see `vesting`.
+    account_grants: AccountGrants,
+    next_grant_id: u64,
+    /// Id of the last snapshot, 0 before the first one.
+    current_snapshot_id: u64,
+    checkpoints: LookupMap<AccountId, Checkpoints>,
+    supply_checkpoints: Checkpoints,
+}
+
+/// Standard of the events that NEP-141 doesn't define: metadata updates,
+/// pauses, freezes, allowances and snapshots. `ft_mint`, `ft_burn` and
+/// `ft_transfer` stay under `nep141`.
+const EVENT_STANDARD: &str = "near-amm-ft";
+
+/// Logs a NEP-297 event of this token, under `EVENT_STANDARD`.
+fn emit_event(event: &str, data: Value) {
+    log!(
+        "EVENT_JSON:{}",
+        json!({
+            "standard": EVENT_STANDARD,
+            "version": "1.0.0",
+            "event": event,
+            "data": [data],
+        })
+    );
+}
+
+/// Charges `account_id` for the storage used since `initial_storage` out of
+/// its `deposit`, and refunds the rest or the storage freed.
+fn settle_storage(account_id: &AccountId, initial_storage: StorageUsage, deposit: Balance) {
+    let storage = env::storage_usage();
+    let refund = if storage > initial_storage {
+        let cost = Balance::from(storage - initial_storage) * env::storage_byte_cost();
+        assert!(
+            deposit >= cost,
+            "Please attach at least {} yoctoNEAR for the storage",
+            cost
+        );
+        deposit - cost
+    } else {
+        deposit + Balance::from(initial_storage - storage) * env::storage_byte_cost()
+    };
+    if refund > 0 {
+        Promise::new(account_id.clone()).transfer(refund);
+    }
 }
 
 #[near_bindgen]
@@ -30,20 +92,46 @@ impl FTContract {
                 reference_hash: None,
                 decimals: 24,
             },
+            None,
         )
     }
 
+    /// Mints `total_supply` to the owner, who can mint more later on as long
+    /// as the supply stays under `max_supply`.
     #[init]
-    pub fn new(owner_id: AccountId, total_supply: U128, metadata: FungibleTokenMetadata) -> Self {
+    pub fn new(
+        owner_id: AccountId,
+        total_supply: U128,
+        metadata: FungibleTokenMetadata,
+        max_supply: Option<U128>,
+    ) -> Self {
         assert!(!env::state_exists(), "Already initialized");
         metadata.assert_valid();
+        let max_supply = max_supply.map(|max_supply| max_supply.0);
+        if let Some(max_supply) = max_supply {
+            assert!(
+                total_supply.0 <= max_supply,
+                "The total supply can't be above the max supply"
+            );
+        }
         let mut this = Self {
             token: FungibleToken::new(b"a".to_vec()),
             metadata: LazyOption::new(b"m".to_vec(), Some(&metadata)),
+            owner_id: owner_id.clone(),
+            max_supply,
+            paused: false,
+            frozen: LookupSet::new(b"f".to_vec()),
+            allowances: LookupMap::new(b"l".to_vec()),
+            grants: UnorderedMap::new(b"g".to_vec()),
+            account_grants: LookupMap::new(b"h".to_vec()),
+            next_grant_id: 0,
+            current_snapshot_id: 0,
+            checkpoints: LookupMap::new(b"c".to_vec()),
+            supply_checkpoints: Vector::new(b"s".to_vec()),
         };
         this.token.internal_register_account(&owner_id);
         this.token.internal_deposit(&owner_id, total_supply.into());
-        near_contract_standards::fungible_token::events::FtMint {
+        FtMint {
             owner_id: &owner_id,
             amount: &total_supply,
             memo: Some("Initial tokens supply is minted"),
@@ -52,7 +140,101 @@ impl FTContract {
         this
     }
 
+    /// Mints `amount` new tokens to the registered `account_id`.
+    #[payable]
+    pub fn ft_mint(&mut self, account_id: AccountId, amount: U128, memo: Option<String>) {
+        assert_one_yocto();
+        self.assert_owner();
+        assert!(amount.0 > 0, "The amount should be a positive number");
+        if let Some(max_supply) = self.max_supply {
+            assert!(
+                self.token.total_supply + amount.0 <= max_supply,
+                "Minting would exceed the max supply of {}",
+                max_supply
+            );
+        }
+        self.internal_deposit(&account_id, amount.0);
+        FtMint {
+            owner_id: &account_id,
+            amount: &amount,
+            memo: memo.as_deref(),
+        }
+        .emit();
+    }
+
+    /// Burns `amount` tokens of the owner.
+    #[payable]
+    pub fn ft_burn(&mut self, amount: U128, memo: Option<String>) {
+        assert_one_yocto();
+        self.assert_owner();
+        assert!(amount.0 > 0, "The amount should be a positive number");
+        self.internal_withdraw(&self.owner_id.clone(), amount.0);
+        FtBurn {
+            owner_id: &self.owner_id,
+            amount: &amount,
+            memo: memo.as_deref(),
+        }
+        .emit();
+    }
+
+    #[payable]
+    pub fn set_icon(&mut self, icon: Option<String>) {
+        let mut metadata = self.metadata_for_update();
+        metadata.icon = icon;
+        self.save_metadata(&metadata, "icon");
+    }
+
+    /// `reference_hash` is the sha256 of the JSON at `reference`, both are
+    /// set or cleared together.
+    #[payable]
+    pub fn set_reference(
+        &mut self,
+        reference: Option<String>,
+        reference_hash: Option<Base64VecU8>,
+    ) {
+        let mut metadata = self.metadata_for_update();
+        metadata.reference = reference;
+        metadata.reference_hash = reference_hash;
+        self.save_metadata(&metadata, "reference");
+    }
+
+    #[payable]
+    pub fn set_name(&mut self, name: String) {
+        let mut metadata = self.metadata_for_update();
+        metadata.name = name;
+        self.save_metadata(&metadata, "name");
+    }
+
+    pub fn ft_max_supply(&self) -> Option<U128> {
+        self.max_supply.map(U128)
+    }
+
+    fn metadata_for_update(&self) -> FungibleTokenMetadata {
+        assert_one_yocto();
+        self.assert_owner();
+        self.metadata.get().unwrap()
+    }
+
+    /// Stores the changed metadata and emits an `ft_metadata_update` event
+    /// naming the changed `field`.
+    fn save_metadata(&mut self, metadata: &FungibleTokenMetadata, field: &str) {
+        metadata.assert_valid();
+        self.metadata.set(metadata);
+        emit_event("ft_metadata_update", json!({ "field": field }));
+    }
+
+    fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "Only the owner can call this method"
+        );
+    }
+
     fn on_account_closed(&mut self, account_id: AccountId, balance: Balance) {
+        // The tokens of the account were burned with it
+        self.checkpoint_balance(&account_id, balance);
+        self.checkpoint_supply(self.token.total_supply + balance);
         log!("Clsed @{} with {}", account_id, balance);
     }
 
@@ -61,7 +243,73 @@ impl FTContract {
     }
 }
 
-near_contract_standards::impl_fungible_token_core!(FTContract, token, on_tokens_burned);
+mod allowances;
+mod compliance;
+mod snapshots;
+#[cfg(feature = "taxed")]
+mod taxed;
+mod vesting;
+use snapshots::Checkpoints;
+use vesting::{AccountGrants, Grant};
+
+#[cfg(not(feature = "taxed"))]
+#[near_bindgen]
+impl FungibleTokenCore for FTContract {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        let sender_id = env::predecessor_account_id();
+        self.assert_can_transfer(&sender_id, &receiver_id);
+        self.checkpoint(&sender_id);
+        self.checkpoint(&receiver_id);
+        self.token.ft_transfer(receiver_id, amount, memo)
+    }
+
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let sender_id = env::predecessor_account_id();
+        self.assert_can_transfer(&sender_id, &receiver_id);
+        self.checkpoint(&sender_id);
+        self.checkpoint(&receiver_id);
+        self.token.ft_transfer_call(receiver_id, amount, memo, msg)
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        self.token.ft_total_supply()
+    }
+
+    fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        self.token.ft_balance_of(account_id)
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenResolver for FTContract {
+    /// Refunds at most what the receiver got, whatever it asked for.
+    #[private]
+    fn ft_resolve_transfer(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        self.checkpoint(&sender_id);
+        self.checkpoint(&receiver_id);
+        self.checkpoint_supply(self.token.total_supply);
+        let (used_amount, burned_amount) =
+            self.token
+                .internal_ft_resolve_transfer(&sender_id, receiver_id, amount);
+        if burned_amount > 0 {
+            self.on_tokens_burned(sender_id, burned_amount);
+        }
+        used_amount.into()
+    }
+}
 near_contract_standards::impl_fungible_token_storage!(FTContract, token, on_account_closed);
 
 #[near_bindgen]
@@ -73,8 +321,13 @@ impl FungibleTokenMetadataProvider for FTContract {
 
 #[cfg(all(test, not(target_arch = "wasm32")))]
 mod tests {
-    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
     use near_sdk::{testing_env, Balance};
+    // Only the transfer_call tests, which need the untaxed token, use these
+    #[cfg(not(feature = "taxed"))]
+    use near_sdk::test_utils::get_created_receipts;
+    #[cfg(not(feature = "taxed"))]
+    use near_sdk::{PromiseResult, RuntimeFeesConfig, VMConfig};
 
     use super::*;
 
@@ -89,6 +342,18 @@ mod tests {
         builder
     }
 
+    fn contract_metadata() -> FungibleTokenMetadata {
+        FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: "Test token".to_string(),
+            symbol: "TT".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 24,
+        }
+    }
+
     #[test]
     fn test_new() {
         let mut context = get_context(accounts(1));
@@ -108,6 +373,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(not(feature = "taxed"))]
     fn test_transfer() {
         let mut context = get_context(accounts(2));
         testing_env!(context.build());
@@ -140,4 +406,389 @@ mod tests {
         );
         assert_eq!(contract.ft_balance_of(accounts(1)).0, transfer_amount);
     }
+
+    #[test]
+    #[cfg(feature = "taxed")]
+    fn test_taxed_transfer() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = FTContract::new_default_meta(accounts(2), TOTAL_SUPPLY.into());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.storage_deposit(None, None);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        let transfer_amount = TOTAL_SUPPLY / 3;
+        contract.ft_transfer(accounts(1), transfer_amount.into(), None);
+
+        let tax = transfer_amount * taxed::TAX / 10_000;
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, transfer_amount - tax);
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY - tax);
+    }
+
+    #[test]
+    fn test_mint_and_burn() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = FTContract::new(
+            accounts(1),
+            TOTAL_SUPPLY.into(),
+            contract_metadata(),
+            Some((2 * TOTAL_SUPPLY).into()),
+        );
+        testing_env!(context.attached_deposit(1).build());
+        contract.ft_mint(accounts(1), TOTAL_SUPPLY.into(), None);
+        contract.ft_burn((TOTAL_SUPPLY / 4).into(), Some("burn".to_string()));
+        assert_eq!(
+            contract.ft_total_supply().0,
+            2 * TOTAL_SUPPLY - TOTAL_SUPPLY / 4
+        );
+        assert_eq!(
+            contract.ft_balance_of(accounts(1)).0,
+            2 * TOTAL_SUPPLY - TOTAL_SUPPLY / 4
+        );
+        assert_eq!(contract.ft_max_supply(), Some((2 * TOTAL_SUPPLY).into()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Minting would exceed the max supply")]
+    fn test_mint_above_max_supply() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = FTContract::new(
+            accounts(1),
+            TOTAL_SUPPLY.into(),
+            contract_metadata(),
+            Some(TOTAL_SUPPLY.into()),
+        );
+        testing_env!(context.attached_deposit(1).build());
+        contract.ft_mint(accounts(1), 1.into(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_mint_not_owner() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = FTContract::new_default_meta(accounts(1), TOTAL_SUPPLY.into());
+        testing_env!(context
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.ft_mint(accounts(2), 1.into(), None);
+    }
+
+    #[test]
+    fn test_set_metadata() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = FTContract::new_default_meta(accounts(1), TOTAL_SUPPLY.into());
+        testing_env!(context.attached_deposit(1).build());
+        contract.set_name("Renamed token".to_string());
+        contract.set_icon(Some("data:image/svg+xml,<svg></svg>".to_string()));
+        contract.set_reference(
+            Some("https://example.com/token.json".to_string()),
+            Some(vec![0; 32].into()),
+        );
+        let metadata = contract.ft_metadata();
+        assert_eq!(metadata.name, "Renamed token");
+        assert_eq!(metadata.icon.unwrap(), "data:image/svg+xml,<svg></svg>");
+        assert_eq!(
+            metadata.reference.unwrap(),
+            "https://example.com/token.json"
+        );
+        assert_eq!(
+            get_logs().last().unwrap(),
+            r#"EVENT_JSON:{"standard":"near-amm-ft","version":"1.0.0","event":"ft_metadata_update","data":[{"field":"reference"}]}"#
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_set_name_not_owner() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = FTContract::new_default_meta(accounts(1), TOTAL_SUPPLY.into());
+        testing_env!(context
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.set_name("Renamed token".to_string());
+    }
+
+    /// Token owned by accounts(2), with accounts(3) standing for the AMM.
+    fn token_with_amm(context: &mut VMContextBuilder) -> FTContract {
+        testing_env!(context.build());
+        let mut contract = FTContract::new_default_meta(accounts(2), TOTAL_SUPPLY.into());
+        testing_env!(context
+            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .predecessor_account_id(accounts(3))
+            .build());
+        contract.storage_deposit(None, None);
+        testing_env!(context
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract
+    }
+
+    #[test]
+    #[should_panic(expected = "The token is paused")]
+    fn test_transfer_while_paused() {
+        let mut context = get_context(accounts(2));
+        let mut contract = token_with_amm(&mut context);
+        contract.pause();
+        assert!(contract.is_paused());
+        contract.ft_transfer(accounts(3), 1_000.into(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "The account charlie is frozen")]
+    fn test_transfer_call_to_amm_while_frozen() {
+        let mut context = get_context(accounts(2));
+        let mut contract = token_with_amm(&mut context);
+        contract.freeze(accounts(2));
+        contract.ft_transfer_call(accounts(3), 1_000.into(), None, "".to_string());
+    }
+
+    #[test]
+    #[cfg(not(feature = "taxed"))]
+    fn test_transfer_call_refunds_frozen_sender() {
+        let mut context = get_context(accounts(2));
+        let mut contract = token_with_amm(&mut context);
+        contract.ft_transfer_call(accounts(3), 1_000.into(), None, "".to_string());
+        contract.freeze(accounts(2));
+        assert!(contract.is_frozen(accounts(2)));
+
+        // The AMM hands back part of the tokens
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(
+                json!(U128(400)).to_string().into_bytes()
+            )]
+        );
+        let used = contract.ft_resolve_transfer(accounts(2), accounts(3), 1_000.into());
+        assert_eq!(used.0, 600);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, TOTAL_SUPPLY - 600);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 600);
+    }
+
+    #[test]
+    #[cfg(not(feature = "taxed"))]
+    fn test_unfreeze_allows_transfers() {
+        let mut context = get_context(accounts(2));
+        let mut contract = token_with_amm(&mut context);
+        contract.freeze(accounts(3));
+        contract.unfreeze(accounts(3));
+        contract.pause();
+        contract.unpause();
+        contract.ft_transfer(accounts(3), 1_000.into(), None);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 1_000);
+    }
+
+    #[test]
+    fn test_approve_and_transfer_from() {
+        let mut context = get_context(accounts(2));
+        let mut contract = token_with_amm(&mut context);
+        testing_env!(context
+            .attached_deposit(env::storage_byte_cost() * 200)
+            .build());
+        contract.ft_approve(accounts(4), 1_000.into());
+        assert_eq!(contract.ft_allowance(accounts(2), accounts(4)).0, 1_000);
+
+        testing_env!(context
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(4))
+            .build());
+        contract.ft_transfer_from(accounts(2), accounts(3), 600.into(), None);
+        assert_eq!(contract.ft_allowance(accounts(2), accounts(4)).0, 400);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 600);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, TOTAL_SUPPLY - 600);
+    }
+
+    #[test]
+    #[cfg(not(feature = "taxed"))]
+    fn test_transfer_from_does_not_call_receiver() {
+        let mut context = get_context(accounts(2));
+        let mut contract = token_with_amm(&mut context);
+        contract.ft_transfer_call(accounts(3), 100.into(), None, "".to_string());
+        // ft_transfer_call calls ft_on_transfer and resolves the refund after
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 2);
+        assert_eq!(receipts[0].receiver_id, accounts(3));
+
+        testing_env!(context
+            .attached_deposit(env::storage_byte_cost() * 200)
+            .build());
+        contract.ft_approve(accounts(4), 100.into());
+        testing_env!(context
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(4))
+            .build());
+        contract.ft_transfer_from(accounts(2), accounts(3), 100.into(), None);
+        // while ft_transfer_from only moves the tokens, the receiver is not
+        // told and nothing comes back, the used allowance refunds its storage
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].receiver_id, accounts(2));
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 200);
+    }
+
+    #[test]
+    #[should_panic(expected = "The allowance of 100 is too low")]
+    fn test_transfer_from_above_allowance() {
+        let mut context = get_context(accounts(2));
+        let mut contract = token_with_amm(&mut context);
+        testing_env!(context
+            .attached_deposit(env::storage_byte_cost() * 200)
+            .build());
+        contract.ft_approve(accounts(4), 100.into());
+        testing_env!(context
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(4))
+            .build());
+        contract.ft_transfer_from(accounts(2), accounts(3), 101.into(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "for the storage")]
+    fn test_approve_without_storage_deposit() {
+        let mut context = get_context(accounts(2));
+        let mut contract = token_with_amm(&mut context);
+        contract.ft_approve(accounts(4), 100.into());
+    }
+
+    #[test]
+    fn test_vesting_after_cliff() {
+        let mut context = get_context(accounts(2));
+        let mut contract = token_with_amm(&mut context);
+        let grant_id = contract.create_grant(accounts(3), 1_000.into(), 0, 100, 1_000);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, TOTAL_SUPPLY - 1_000);
+
+        testing_env!(context
+            .block_timestamp(50_000_000_000)
+            .predecessor_account_id(accounts(3))
+            .build());
+        assert_eq!(
+            contract.get_account_grants(accounts(3)),
+            json!([{
+                "grant_id": 0,
+                "beneficiary_id": accounts(3),
+                "total": "1000",
+                "start": 0,
+                "cliff": 100,
+                "duration": 1_000,
+                "revoked": false,
+                "vested": "0",
+                "claimable": "0",
+            }])
+            .to_string()
+        );
+
+        testing_env!(context.block_timestamp(500_000_000_000).build());
+        assert_eq!(contract.claim_vested(grant_id).0, 500);
+        testing_env!(context.block_timestamp(2_000_000_000_000).build());
+        assert_eq!(contract.claim_vested(grant_id).0, 500);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 1_000);
+        assert_eq!(contract.get_account_grants(accounts(3)), "[]");
+    }
+
+    #[test]
+    fn test_revoke_grant_returns_unvested() {
+        let mut context = get_context(accounts(2));
+        let mut contract = token_with_amm(&mut context);
+        let grant_id = contract.create_grant(accounts(3), 1_000.into(), 0, 0, 1_000);
+
+        testing_env!(context.block_timestamp(250_000_000_000).build());
+        assert_eq!(contract.revoke_grant(grant_id).0, 750);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, TOTAL_SUPPLY - 250);
+
+        testing_env!(context
+            .block_timestamp(2_000_000_000_000)
+            .predecessor_account_id(accounts(3))
+            .build());
+        assert_eq!(contract.claim_vested(grant_id).0, 250);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 250);
+        assert_eq!(contract.get_account_grants(accounts(3)), "[]");
+    }
+
+    #[test]
+    #[should_panic(expected = "Nothing to claim")]
+    fn test_claim_before_cliff() {
+        let mut context = get_context(accounts(2));
+        let mut contract = token_with_amm(&mut context);
+        let grant_id = contract.create_grant(accounts(3), 1_000.into(), 0, 100, 1_000);
+        testing_env!(context
+            .block_timestamp(99_000_000_000)
+            .predecessor_account_id(accounts(3))
+            .build());
+        contract.claim_vested(grant_id);
+    }
+
+    #[test]
+    #[cfg(not(feature = "taxed"))]
+    fn test_balances_at_snapshots() {
+        let mut context = get_context(accounts(2));
+        let mut contract = token_with_amm(&mut context);
+        contract.ft_transfer(accounts(3), 1_000.into(), None);
+        assert!(contract.checkpoints.get(&accounts(3)).is_none());
+
+        let first = contract.snapshot();
+        contract.ft_transfer(accounts(3), 500.into(), None);
+        contract.ft_transfer(accounts(3), 500.into(), None);
+        let second = contract.snapshot();
+        let third = contract.snapshot();
+        contract.ft_mint(accounts(3), 100.into(), None);
+
+        // One checkpoint per snapshot followed by a change
+        assert_eq!(
+            contract.checkpoints.get(&accounts(3)).unwrap().to_vec(),
+            vec![(first, 1_000), (third, 2_000)]
+        );
+        assert_eq!(contract.ft_balance_of_at(accounts(3), first).0, 1_000);
+        assert_eq!(contract.ft_balance_of_at(accounts(3), second).0, 2_000);
+        assert_eq!(contract.ft_balance_of_at(accounts(3), third).0, 2_000);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 2_100);
+        assert_eq!(
+            contract.ft_balance_of_at(accounts(2), first).0,
+            TOTAL_SUPPLY - 1_000
+        );
+        assert_eq!(contract.ft_total_supply_at(third).0, TOTAL_SUPPLY);
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY + 100);
+    }
+
+    #[test]
+    #[cfg(not(feature = "taxed"))]
+    fn test_supply_at_many_snapshots() {
+        let mut context = get_context(accounts(2));
+        let mut contract = token_with_amm(&mut context);
+        for minted in 1..=5 {
+            contract.snapshot();
+            contract.ft_mint(accounts(3), minted.into(), None);
+        }
+        assert_eq!(contract.supply_checkpoints.len(), 5);
+        let mut supply = TOTAL_SUPPLY;
+        for snapshot_id in 1..=5 {
+            assert_eq!(contract.ft_total_supply_at(snapshot_id).0, supply);
+            supply += snapshot_id as Balance;
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Snapshot 1 doesn't exist")]
+    fn test_balance_at_future_snapshot() {
+        let mut context = get_context(accounts(2));
+        let contract = token_with_amm(&mut context);
+        contract.ft_balance_of_at(accounts(3), 1);
+    }
 }
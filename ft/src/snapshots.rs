@@ -0,0 +1,140 @@
+//! Balance snapshots, for governance.
+//!
+//! `snapshot` only bumps the current snapshot id. Balances are checkpointed
+//! lazily: the first time a balance changes after a snapshot, the balance it
+//! had before is stored under the current id. The balance at a snapshot is
+//! then the first checkpoint taken at or after it, or the current balance
+//! when it has not changed since.
+//!
+//! The checkpoints of every balance, and of the total supply, are kept in
+//! their own `Vector`, so a change only writes the new checkpoint.
+
+use near_sdk::collections::Vector;
+use near_sdk::json_types::U128;
+use near_sdk::{assert_one_yocto, env, near_bindgen, AccountId, Balance};
+
+use crate::*;
+
+/// Checkpoints of one balance, by increasing snapshot id.
+pub type Checkpoints = Vector<(u64, Balance)>;
+
+#[near_bindgen]
+impl FTContract {
+    /// Takes a snapshot of all the balances, returns its id.
+    #[payable]
+    pub fn snapshot(&mut self) -> u64 {
+        assert_one_yocto();
+        self.assert_owner();
+        self.current_snapshot_id += 1;
+        emit_event(
+            "ft_snapshot",
+            json!({ "snapshot_id": self.current_snapshot_id }),
+        );
+        self.current_snapshot_id
+    }
+
+    pub fn ft_balance_of_at(&self, account_id: AccountId, snapshot_id: u64) -> U128 {
+        self.assert_valid_snapshot(snapshot_id);
+        U128(
+            self.checkpoints
+                .get(&account_id)
+                .and_then(|checkpoints| balance_at(&checkpoints, snapshot_id))
+                .unwrap_or_else(|| self.token.accounts.get(&account_id).unwrap_or(0)),
+        )
+    }
+
+    pub fn ft_total_supply_at(&self, snapshot_id: u64) -> U128 {
+        self.assert_valid_snapshot(snapshot_id);
+        U128(balance_at(&self.supply_checkpoints, snapshot_id).unwrap_or(self.token.total_supply))
+    }
+
+    pub fn get_current_snapshot_id(&self) -> u64 {
+        self.current_snapshot_id
+    }
+
+    pub(crate) fn internal_transfer(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        amount: Balance,
+        memo: Option<String>,
+    ) {
+        self.checkpoint(sender_id);
+        self.checkpoint(receiver_id);
+        self.token
+            .internal_transfer(sender_id, receiver_id, amount, memo);
+    }
+
+    pub(crate) fn internal_deposit(&mut self, account_id: &AccountId, amount: Balance) {
+        self.checkpoint(account_id);
+        self.checkpoint_supply(self.token.total_supply);
+        self.token.internal_deposit(account_id, amount);
+    }
+
+    pub(crate) fn internal_withdraw(&mut self, account_id: &AccountId, amount: Balance) {
+        self.checkpoint(account_id);
+        self.checkpoint_supply(self.token.total_supply);
+        self.token.internal_withdraw(account_id, amount);
+    }
+
+    /// Stores the balance of the account before it changes, if it is the
+    /// first change since the last snapshot.
+    pub(crate) fn checkpoint(&mut self, account_id: &AccountId) {
+        let balance = self.token.accounts.get(account_id).unwrap_or(0);
+        self.checkpoint_balance(account_id, balance);
+    }
+
+    pub(crate) fn checkpoint_balance(&mut self, account_id: &AccountId, balance: Balance) {
+        if self.current_snapshot_id == 0 {
+            return;
+        }
+        let mut checkpoints = self.checkpoints.get(account_id).unwrap_or_else(|| {
+            Vector::new([b"k".as_slice(), &env::sha256(account_id.as_bytes())].concat())
+        });
+        if push_checkpoint(&mut checkpoints, self.current_snapshot_id, balance) {
+            self.checkpoints.insert(account_id, &checkpoints);
+        }
+    }
+
+    pub(crate) fn checkpoint_supply(&mut self, total_supply: Balance) {
+        if self.current_snapshot_id != 0 {
+            push_checkpoint(
+                &mut self.supply_checkpoints,
+                self.current_snapshot_id,
+                total_supply,
+            );
+        }
+    }
+
+    fn assert_valid_snapshot(&self, snapshot_id: u64) {
+        assert!(
+            snapshot_id > 0 && snapshot_id <= self.current_snapshot_id,
+            "Snapshot {} doesn't exist",
+            snapshot_id
+        );
+    }
+}
+
+/// Returns whether the checkpoint was added.
+fn push_checkpoint(checkpoints: &mut Checkpoints, snapshot_id: u64, balance: Balance) -> bool {
+    let len = checkpoints.len();
+    if len > 0 && checkpoints.get(len - 1).unwrap().0 == snapshot_id {
+        return false;
+    }
+    checkpoints.push(&(snapshot_id, balance));
+    true
+}
+
+/// Binary search of the first checkpoint taken at or after the snapshot.
+fn balance_at(checkpoints: &Checkpoints, snapshot_id: u64) -> Option<Balance> {
+    let (mut low, mut high) = (0, checkpoints.len());
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if checkpoints.get(middle).unwrap().0 < snapshot_id {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    checkpoints.get(low).map(|(_, balance)| balance)
+}
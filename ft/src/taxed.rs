@@ -0,0 +1,95 @@
+//! Fee-on-transfer variant of the token, built with the `taxed` feature.
+//!
+//! Every transfer burns `TAX` basis points of the amount from what the
+//! receiver gets, but `ft_on_transfer` is still called with the full amount,
+//! like some taxed tokens in the wild do.
+
+use near_contract_standards::fungible_token::core_impl::ext_fungible_token_receiver;
+use near_sdk::json_types::U128;
+use near_sdk::{assert_one_yocto, env, ext_contract, log, near_bindgen, AccountId, Balance, Gas};
+
+use crate::*;
+
+/// Tax of every transfer, in basis points.
+pub const TAX: u128 = 100;
+
+const GAS_FOR_RESOLVE_TRANSFER: Gas = Gas(5_000_000_000_000);
+const GAS_FOR_FT_TRANSFER_CALL: Gas = Gas(25_000_000_000_000 + GAS_FOR_RESOLVE_TRANSFER.0);
+
+#[ext_contract(ext_self)]
+trait FungibleTokenResolver {
+    fn ft_resolve_transfer(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> U128;
+}
+
+impl FTContract {
+    /// Burns the tax from the sender, returns what is left to transfer.
+    fn take_tax(&mut self, sender_id: &AccountId, amount: Balance) -> Balance {
+        let tax = amount * TAX / 10_000;
+        if tax > 0 {
+            self.internal_withdraw(sender_id, tax);
+            log!("Tax of {} burned from {}", tax, sender_id);
+        }
+        amount - tax
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenCore for FTContract {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        self.assert_can_transfer(&sender_id, &receiver_id);
+        let received = self.take_tax(&sender_id, amount.0);
+        self.internal_transfer(&sender_id, &receiver_id, received, memo);
+    }
+
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        assert_one_yocto();
+        assert!(
+            env::prepaid_gas() > GAS_FOR_FT_TRANSFER_CALL,
+            "More gas is required"
+        );
+        let sender_id = env::predecessor_account_id();
+        self.assert_can_transfer(&sender_id, &receiver_id);
+        let received = self.take_tax(&sender_id, amount.0);
+        self.internal_transfer(&sender_id, &receiver_id, received, memo);
+        ext_fungible_token_receiver::ft_on_transfer(
+            sender_id.clone(),
+            amount,
+            msg,
+            receiver_id.clone(),
+            0,
+            env::prepaid_gas() - GAS_FOR_FT_TRANSFER_CALL,
+        )
+        .then(ext_self::ft_resolve_transfer(
+            sender_id,
+            receiver_id,
+            U128(received),
+            env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_TRANSFER,
+        ))
+        .into()
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        self.token.ft_total_supply()
+    }
+
+    fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        self.token.ft_balance_of(account_id)
+    }
+}
@@ -0,0 +1,196 @@
+//! Vesting grants.
+//!
+//! The owner escrows tokens in the contract account for a beneficiary. They
+//! vest linearly over `duration` seconds from `start`, nothing before the
+//! cliff, and the beneficiary claims what vested so far. Revoking a grant
+//! returns the unvested tokens to the owner, the vested ones stay claimable.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::UnorderedSet;
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::{json, Value};
+use near_sdk::{assert_one_yocto, env, near_bindgen, AccountId, Balance};
+
+use u256::U256;
+
+use crate::*;
+
+const NANOSECONDS_IN_SECOND: u64 = 1_000_000_000;
+
+/// Ids of the grants of every beneficiary, each in its own set.
+pub type AccountGrants = LookupMap<AccountId, UnorderedSet<u64>>;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Grant {
+    pub beneficiary_id: AccountId,
+    pub total: Balance,
+    pub claimed: Balance,
+    /// Block timestamp, in nanoseconds, the vesting starts from.
+    pub start: u64,
+    /// Seconds after `start` before anything vests.
+    pub cliff: u64,
+    /// Seconds after `start` when everything has vested.
+    pub duration: u64,
+    pub revoked: bool,
+}
+
+impl Grant {
+    /// Tokens vested at `timestamp`, in nanoseconds.
+    pub fn vested(&self, timestamp: u64) -> Balance {
+        let elapsed = timestamp.saturating_sub(self.start) / NANOSECONDS_IN_SECOND;
+        if self.revoked || elapsed >= self.duration {
+            self.total
+        } else if elapsed < self.cliff {
+            0
+        } else {
+            (U256::from(self.total) * U256::from(elapsed) / U256::from(self.duration)).as_u128()
+        }
+    }
+}
+
+#[near_bindgen]
+impl FTContract {
+    /// Escrows `total` tokens of the owner for `beneficiary_id`, returns the
+    /// grant id.
+    #[payable]
+    pub fn create_grant(
+        &mut self,
+        beneficiary_id: AccountId,
+        total: U128,
+        start: u64,
+        cliff: u64,
+        duration: u64,
+    ) -> u64 {
+        assert_one_yocto();
+        self.assert_owner();
+        assert!(total.0 > 0, "The amount should be a positive number");
+        assert!(duration > 0, "The duration should be a positive number");
+        assert!(
+            cliff <= duration,
+            "The cliff can't be longer than the duration"
+        );
+        assert!(
+            self.token.accounts.contains_key(&beneficiary_id),
+            "The account {} is not registered",
+            beneficiary_id
+        );
+        let escrow_id = env::current_account_id();
+        if !self.token.accounts.contains_key(&escrow_id) {
+            self.token.internal_register_account(&escrow_id);
+        }
+        self.internal_transfer(&self.owner_id.clone(), &escrow_id, total.0, None);
+
+        let grant_id = self.next_grant_id;
+        self.next_grant_id += 1;
+        let mut grant_ids = self.account_grants.get(&beneficiary_id).unwrap_or_else(|| {
+            UnorderedSet::new([b"v".as_slice(), &env::sha256(beneficiary_id.as_bytes())].concat())
+        });
+        grant_ids.insert(&grant_id);
+        self.account_grants.insert(&beneficiary_id, &grant_ids);
+        self.grants.insert(
+            &grant_id,
+            &Grant {
+                beneficiary_id,
+                total: total.0,
+                claimed: 0,
+                start,
+                cliff,
+                duration,
+                revoked: false,
+            },
+        );
+        grant_id
+    }
+
+    /// Sends the vested tokens not claimed yet to the beneficiary.
+    pub fn claim_vested(&mut self, grant_id: u64) -> U128 {
+        let mut grant = self.grants.get(&grant_id).expect("Grant not found");
+        assert_eq!(
+            env::predecessor_account_id(),
+            grant.beneficiary_id,
+            "Only the beneficiary can claim"
+        );
+        let claimable = grant.vested(env::block_timestamp()) - grant.claimed;
+        assert!(claimable > 0, "Nothing to claim");
+        let escrow_id = env::current_account_id();
+        self.assert_can_transfer(&escrow_id, &grant.beneficiary_id);
+
+        grant.claimed += claimable;
+        self.save_grant(grant_id, &grant);
+        self.internal_transfer(&escrow_id, &grant.beneficiary_id, claimable, None);
+        U128(claimable)
+    }
+
+    /// Stops the vesting and gives the unvested tokens back to the owner.
+    /// Returns their amount.
+    #[payable]
+    pub fn revoke_grant(&mut self, grant_id: u64) -> U128 {
+        assert_one_yocto();
+        self.assert_owner();
+        let mut grant = self.grants.get(&grant_id).expect("Grant not found");
+        assert!(!grant.revoked, "The grant is already revoked");
+        let vested = grant.vested(env::block_timestamp());
+        let unvested = grant.total - vested;
+        grant.total = vested;
+        grant.revoked = true;
+        self.save_grant(grant_id, &grant);
+        if unvested > 0 {
+            self.internal_transfer(
+                &env::current_account_id(),
+                &self.owner_id.clone(),
+                unvested,
+                None,
+            );
+        }
+        U128(unvested)
+    }
+
+    pub fn get_grant(&self, grant_id: u64) -> String {
+        let grant = self.grants.get(&grant_id).expect("Grant not found");
+        grant_json(grant_id, &grant).to_string()
+    }
+
+    pub fn get_account_grants(&self, account_id: AccountId) -> String {
+        let grant_ids = self
+            .account_grants
+            .get(&account_id)
+            .map_or_else(Vec::new, |grant_ids| grant_ids.to_vec());
+        let grants: Vec<_> = grant_ids
+            .into_iter()
+            .map(|grant_id| grant_json(grant_id, &self.grants.get(&grant_id).unwrap()))
+            .collect();
+        json!(grants).to_string()
+    }
+
+    /// Stores the grant, or drops it once everything is claimed.
+    fn save_grant(&mut self, grant_id: u64, grant: &Grant) {
+        if grant.claimed < grant.total {
+            self.grants.insert(&grant_id, grant);
+            return;
+        }
+        self.grants.remove(&grant_id);
+        let mut grant_ids = self.account_grants.get(&grant.beneficiary_id).unwrap();
+        grant_ids.remove(&grant_id);
+        if grant_ids.is_empty() {
+            self.account_grants.remove(&grant.beneficiary_id);
+        } else {
+            self.account_grants
+                .insert(&grant.beneficiary_id, &grant_ids);
+        }
+    }
+}
+
+fn grant_json(grant_id: u64, grant: &Grant) -> Value {
+    let vested = grant.vested(env::block_timestamp());
+    json!({
+        "grant_id": grant_id,
+        "beneficiary_id": grant.beneficiary_id,
+        "total": U128(grant.total),
+        "start": grant.start,
+        "cliff": grant.cliff,
+        "duration": grant.duration,
+        "revoked": grant.revoked,
+        "vested": U128(vested),
+        "claimable": U128(vested - grant.claimed),
+    })
+}
@@ -1,6 +1,11 @@
-use crate::utils::{init, meta_a, meta_b, AMM_ID};
+use crate::utils::{
+    init, init_with_code, meta_a, meta_b, AMM_ID, AMM_V1_WASM_BYTES, AMM_WASM_BYTES,
+    AMM_WITHOUT_MINIMUM_LIQUIDITY_WASM_BYTES, FT_TAXED_WASM_BYTES, FT_WASM_BYTES,
+};
+use amm::AMMContract;
+use ft::FTContractContract as FTContract;
 use near_sdk::json_types::U128;
-use near_sdk_sim::{call, to_yocto, view};
+use near_sdk_sim::{call, to_yocto, view, ContractAccount, UserAccount, DEFAULT_GAS};
 
 #[test]
 fn simulate_total_supply() {
@@ -262,9 +267,10 @@ fn test_add_and_exclude_pool() {
     let owner_balance_amm_b: U128 =
         view!(amm.ft_balance_of(ft_b.account_id(), amm.account_id())).unwrap_json();
 
+    // The first mint locks MINIMUM_LIQUIDITY shares for good
     assert_eq!(
         rick_balance_amm_amm.0,
-        send_a_tokens_to_pool + send_b_tokens_to_pool
+        send_a_tokens_to_pool + send_b_tokens_to_pool - 1_000
     );
     assert_eq!(
         rick_balance_amm_a.0,
@@ -287,9 +293,14 @@ fn test_add_and_exclude_pool() {
         amm.exclude_token_from_pool(ft_a.account_id(), send_a_tokens_to_pool.into(), None)
     )
     .assert_success();
+    // The locked shares keep 1_000 of B in the pool
     call!(
         rick,
-        amm.exclude_token_from_pool(ft_b.account_id(), send_b_tokens_to_pool.into(), None)
+        amm.exclude_token_from_pool(
+            ft_b.account_id(),
+            (send_b_tokens_to_pool - 1_000).into(),
+            None
+        )
     )
     .assert_success();
 
@@ -305,9 +316,9 @@ fn test_add_and_exclude_pool() {
         view!(amm.ft_balance_of(ft_b.account_id(), amm.account_id())).unwrap_json();
     assert_eq!(rick_balance_amm_amm.0, 0);
     assert_eq!(rick_balance_amm_a.0, rick_balance_amm_a_before.0);
-    assert_eq!(rick_balance_amm_b.0, rick_balance_amm_b_before.0);
+    assert_eq!(rick_balance_amm_b.0, rick_balance_amm_b_before.0 - 1_000);
     assert_eq!(owner_balance_amm_a.0, 0);
-    assert_eq!(owner_balance_amm_b.0, 0);
+    assert_eq!(owner_balance_amm_b.0, 1_000);
 }
 
 #[test]
@@ -412,9 +423,10 @@ fn test_swap_tokens() {
         view!(amm.ft_balance_of(ft_a.account_id(), amm.account_id())).unwrap_json();
     let owner_balance_amm_b: U128 =
         view!(amm.ft_balance_of(ft_b.account_id(), amm.account_id())).unwrap_json();
+    // The first mint locks MINIMUM_LIQUIDITY shares for good
     assert_eq!(
         rick_balance_amm_amm.0,
-        send_a_tokens_to_pool + send_b_tokens_to_pool
+        send_a_tokens_to_pool + send_b_tokens_to_pool - 1_000
     );
     assert_eq!(
         rick_balance_amm_a.0,
@@ -443,7 +455,7 @@ fn test_swap_tokens() {
         view!(amm.ft_balance_of(ft_b.account_id(), amm.account_id())).unwrap_json();
     let outcome = call!(
         rick,
-        amm.swap(buy_token, sell_token, sell_token_amount.into())
+        amm.swap(buy_token, sell_token, sell_token_amount.into(), None)
     );
     outcome.assert_success();
     let buy_amount: U128 = outcome.unwrap_json();
@@ -477,3 +489,246 @@ fn test_swap_tokens() {
         owner_balance_amm_b_prev.0 - buy_amount.0
     );
 }
+
+/// Pool of token A only where Rick, the attacker, and root, the victim, both
+/// hold 10_000 on their internal balances.
+fn init_first_depositor_attack(
+    amm_wasm_bytes: &[u8],
+) -> (
+    UserAccount,
+    ContractAccount<FTContract>,
+    ContractAccount<AMMContract>,
+    UserAccount,
+) {
+    let (root, ft_a, _ft_b, amm, rick) = init_with_code(1_000_000, &FT_WASM_BYTES, amm_wasm_bytes);
+    call!(root, amm.set_metadata_a(meta_a())).assert_success();
+    call!(root, amm.set_metadata_b(meta_b())).assert_success();
+    call!(
+        root,
+        ft_a.ft_transfer(rick.account_id(), 200_000.into(), None),
+        deposit = 1
+    )
+    .assert_success();
+    for user in [&rick, &root] {
+        for token_name in [ft_a.account_id(), amm.account_id()] {
+            call!(
+                root,
+                amm.storage_deposit(token_name, user.account_id(), None),
+                deposit = near_sdk::env::storage_byte_cost() * 250
+            )
+            .assert_success();
+        }
+        call!(
+            user,
+            ft_a.ft_transfer_call(AMM_ID.parse().unwrap(), 10_000.into(), None, "".to_string()),
+            deposit = 1
+        )
+        .assert_success();
+    }
+    (root, ft_a, amm, rick)
+}
+
+/// Rick mints the first shares, then donates `donation` straight to the pool
+/// to inflate the price of a share.
+fn mint_first_shares_and_donate(
+    ft_a: &ContractAccount<FTContract>,
+    amm: &ContractAccount<AMMContract>,
+    rick: &UserAccount,
+    deposit: u128,
+    donation: u128,
+) {
+    call!(
+        rick,
+        amm.add_token_to_pool(ft_a.account_id(), deposit.into(), None)
+    )
+    .assert_success();
+    call!(
+        rick,
+        ft_a.ft_transfer(AMM_ID.parse().unwrap(), donation.into(), None),
+        deposit = 1
+    )
+    .assert_success();
+    call!(amm.user_account, amm.sync()).assert_success();
+}
+
+#[test]
+fn test_first_depositor_inflation_attack() {
+    // The AMM as it was before the first mint locked MINIMUM_LIQUIDITY
+    let (root, ft_a, amm, rick) =
+        init_first_depositor_attack(&AMM_WITHOUT_MINIMUM_LIQUIDITY_WASM_BYTES);
+    mint_first_shares_and_donate(&ft_a, &amm, &rick, 1, 100_000);
+    let rick_shares: U128 =
+        view!(amm.ft_balance_of(amm.account_id(), rick.account_id())).unwrap_json();
+    assert_eq!(rick_shares.0, 1);
+
+    // The victim gets 10_000 * 1 / 100_001 = 0 shares for its deposit
+    call!(
+        root,
+        amm.add_token_to_pool(ft_a.account_id(), 10_000.into(), None)
+    )
+    .assert_success();
+    let root_shares: U128 =
+        view!(amm.ft_balance_of(amm.account_id(), root.account_id())).unwrap_json();
+    let root_balance_amm_a: U128 =
+        view!(amm.ft_balance_of(ft_a.account_id(), root.account_id())).unwrap_json();
+    assert_eq!(root_shares.0, 0);
+    assert_eq!(root_balance_amm_a.0, 0);
+
+    // and the single share of Rick takes the whole pool, the deposit of the
+    // victim included
+    call!(
+        rick,
+        amm.exclude_token_from_pool(ft_a.account_id(), 110_001.into(), None)
+    )
+    .assert_success();
+    let rick_balance_amm_a: U128 =
+        view!(amm.ft_balance_of(ft_a.account_id(), rick.account_id())).unwrap_json();
+    assert_eq!(rick_balance_amm_a.0, 10_000 - 1 + 110_001);
+}
+
+#[test]
+fn test_first_depositor_inflation_attack_is_prevented() {
+    let (root, ft_a, amm, rick) = init_first_depositor_attack(&AMM_WASM_BYTES);
+    // The smallest first deposit mints a single share for Rick
+    mint_first_shares_and_donate(&ft_a, &amm, &rick, 1_001, 100_000);
+    let rick_shares: U128 =
+        view!(amm.ft_balance_of(amm.account_id(), rick.account_id())).unwrap_json();
+    assert_eq!(rick_shares.0, 1);
+
+    // The locked shares keep the price of a share down, the victim gets
+    // 10_000 * 1_001 / 101_001 = 99 shares
+    call!(
+        root,
+        amm.add_token_to_pool(ft_a.account_id(), 10_000.into(), None)
+    )
+    .assert_success();
+    let root_shares: U128 =
+        view!(amm.ft_balance_of(amm.account_id(), root.account_id())).unwrap_json();
+    assert_eq!(root_shares.0, 99);
+
+    // which are still worth 99 * 111_001 / 1_100 = 9_990 of its 10_000
+    assert!(!call!(
+        root,
+        amm.exclude_token_from_pool(ft_a.account_id(), 9_991.into(), None)
+    )
+    .is_ok());
+    call!(
+        root,
+        amm.exclude_token_from_pool(ft_a.account_id(), 9_990.into(), None)
+    )
+    .assert_success();
+
+    // while the single share of Rick is worth 100 of the 101_001 he put in
+    assert!(!call!(
+        rick,
+        amm.exclude_token_from_pool(ft_a.account_id(), 101.into(), None)
+    )
+    .is_ok());
+    call!(
+        rick,
+        amm.exclude_token_from_pool(ft_a.account_id(), 100.into(), None)
+    )
+    .assert_success();
+    let rick_balance_amm_a: U128 =
+        view!(amm.ft_balance_of(ft_a.account_id(), rick.account_id())).unwrap_json();
+    assert_eq!(rick_balance_amm_a.0, 10_000 - 1_001 + 100);
+}
+
+#[test]
+fn test_upgrade_from_first_release_keeps_balances() {
+    let (root, ft_a, _ft_b, amm, rick) = init_with_code(280_000, &FT_WASM_BYTES, &AMM_V1_WASM_BYTES);
+    call!(
+        root,
+        ft_a.ft_transfer(rick.account_id(), 20_000.into(), None),
+        deposit = 1
+    )
+    .assert_success();
+    for token_name in [ft_a.account_id(), amm.account_id()] {
+        call!(
+            root,
+            amm.storage_deposit(token_name, rick.account_id(), None),
+            deposit = near_sdk::env::storage_byte_cost() * 250
+        )
+        .assert_success();
+    }
+    call!(
+        rick,
+        ft_a.ft_transfer_call(AMM_ID.parse().unwrap(), 10_000.into(), None, "".to_string()),
+        deposit = 1
+    )
+    .assert_success();
+    call!(root, amm.set_metadata_a(meta_a())).assert_success();
+    call!(root, amm.set_metadata_b(meta_b())).assert_success();
+    call!(
+        rick,
+        amm.add_token_to_pool(ft_a.account_id(), 8_000.into(), None)
+    )
+    .assert_success();
+
+    // Deploy the current code over the state of the first release
+    amm.user_account
+        .create_transaction(amm.account_id())
+        .deploy_contract(AMM_WASM_BYTES.to_vec())
+        .function_call("migrate".to_string(), b"{}".to_vec(), DEFAULT_GAS, 0)
+        .submit()
+        .assert_success();
+
+    let version: String = view!(amm.get_state_version()).unwrap_json();
+    assert_eq!(version, "V2");
+    let rick_balance_amm_a: U128 =
+        view!(amm.ft_balance_of(ft_a.account_id(), rick.account_id())).unwrap_json();
+    let rick_balance_amm_amm: U128 =
+        view!(amm.ft_balance_of(amm.account_id(), rick.account_id())).unwrap_json();
+    let owner_balance_amm_a: U128 =
+        view!(amm.ft_balance_of(ft_a.account_id(), amm.account_id())).unwrap_json();
+    assert_eq!(rick_balance_amm_a.0, 2_000);
+    assert_eq!(rick_balance_amm_amm.0, 8_000);
+    assert_eq!(owner_balance_amm_a.0, 8_000);
+
+    // and the migrated pool keeps working
+    call!(
+        rick,
+        amm.exclude_token_from_pool(ft_a.account_id(), 8_000.into(), None)
+    )
+    .assert_success();
+}
+
+#[test]
+fn test_measured_deposit_of_taxed_token() {
+    // Token A burns 1% of every transfer
+    let (root, ft_a, _ft_b, amm, rick) =
+        init_with_code(1_000_000, &FT_TAXED_WASM_BYTES, &AMM_WASM_BYTES);
+    call!(
+        root,
+        ft_a.ft_transfer(rick.account_id(), 100_000.into(), None),
+        deposit = 1
+    )
+    .assert_success();
+    call!(
+        root,
+        amm.storage_deposit(ft_a.account_id(), rick.account_id(), None),
+        deposit = near_sdk::env::storage_byte_cost() * 250
+    )
+    .assert_success();
+    call!(
+        amm.user_account,
+        amm.set_measured_deposits(ft_a.account_id(), true)
+    )
+    .assert_success();
+
+    call!(
+        rick,
+        ft_a.ft_transfer_call(AMM_ID.parse().unwrap(), 10_000.into(), None, "".to_string()),
+        deposit = 1
+    )
+    .assert_success();
+
+    // Rick is credited with what the AMM received, not with what he sent
+    let rick_balance_amm_a: U128 =
+        view!(amm.ft_balance_of(ft_a.account_id(), rick.account_id())).unwrap_json();
+    let amm_balance_a: U128 = view!(ft_a.ft_balance_of(amm.account_id())).unwrap_json();
+    assert_eq!(rick_balance_amm_a.0, 9_900);
+    assert_eq!(amm_balance_a.0, 9_900);
+    let rick_balance_a: U128 = view!(ft_a.ft_balance_of(rick.account_id())).unwrap_json();
+    assert_eq!(rick_balance_a.0, 99_000 - 10_000);
+}
@@ -12,7 +12,10 @@ pub const AMM_ID: &str = "amm";
 // Load in contract bytes at runtime
 near_sdk_sim::lazy_static_include::lazy_static_include_bytes! {
     FT_WASM_BYTES => "res/ft.wasm",
+    FT_TAXED_WASM_BYTES => "res/ft_taxed.wasm",
     AMM_WASM_BYTES => "res/amm.wasm",
+    AMM_V1_WASM_BYTES => "res/amm_v1.wasm",
+    AMM_WITHOUT_MINIMUM_LIQUIDITY_WASM_BYTES => "res/amm_without_minimum_liquidity.wasm",
 }
 
 // Register the given `user` with FT contract
@@ -62,18 +65,34 @@ pub fn init(
     ContractAccount<FTContract>,
     ContractAccount<AMMContract>,
     UserAccount,
+) {
+    init_with_code(initial_balance, &FT_WASM_BYTES, &AMM_WASM_BYTES)
+}
+
+/// Same as `init`, deploying the given code for token A and the AMM.
+pub fn init_with_code(
+    initial_balance: u128,
+    token_a_wasm_bytes: &[u8],
+    amm_wasm_bytes: &[u8],
+) -> (
+    UserAccount,
+    ContractAccount<FTContract>,
+    ContractAccount<FTContract>,
+    ContractAccount<AMMContract>,
+    UserAccount,
 ) {
     let root = init_simulator(None);
     // Init Token A contract
     let token_a_contract = deploy!(
         contract: FTContract,
         contract_id: FT_A_ID,
-        bytes: &FT_WASM_BYTES,
+        bytes: token_a_wasm_bytes,
         signer_account: root,
         init_method: new(
             root.account_id(),
             initial_balance.into(),
-            meta_a()
+            meta_a(),
+            None
         )
     );
     // Init Token B contract
@@ -85,7 +104,8 @@ pub fn init(
         init_method: new(
             root.account_id(),
             initial_balance.into(),
-            meta_b()
+            meta_b(),
+            None
         )
     );
 
@@ -97,12 +117,13 @@ pub fn init(
     let amm_contract = deploy!(
         contract: AMMContract,
         contract_id: AMM_ID,
-        bytes: &AMM_WASM_BYTES,
+        bytes: amm_wasm_bytes,
         signer_account: root,
         init_method: new(
             AMM_ID.parse().unwrap(),
             token_a_contract.account_id(),
-            token_b_contract.account_id()
+            token_b_contract.account_id(),
+            None
         )
     );
     register_user(FT_A_ID, &amm_contract.user_account);
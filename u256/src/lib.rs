@@ -0,0 +1,10 @@
+//! 256 bits integer shared by the contracts, for products of two `Balance`s.
+
+#![no_std]
+// The code generated by `construct_uint!` doesn't follow clippy's advice
+#![allow(clippy::all)]
+
+uint::construct_uint! {
+    /// Wide integer for products that can overflow a `Balance`.
+    pub struct U256(4);
+}